@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hint::black_box;
+use std::time::Instant;
+
+use nes_emulator::bus::BusOP;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::{CPU, Mem};
+use nes_emulator::opcodes::{CPU_OPS_CODES, OPCODE_TABLE};
+
+// Instructions nestest runs from $C000 before it starts poking the APU
+const NESTEST_INSTRUCTIONS: usize = 8990;
+const ROUNDS: usize = 2000;
+
+// Flat 64K of memory so the benchmark measures the CPU and not the bus
+struct FlatBus {
+    mem: Vec<u8>,
+    cycles: usize,
+}
+
+impl Mem for FlatBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.mem[addr as usize]
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        self.mem[addr as usize] = data;
+    }
+}
+
+impl BusOP for FlatBus {
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn cycles(&mut self) -> usize {
+        self.cycles
+    }
+}
+
+// The decoder CPU::step had before OPCODE_TABLE, kept as the baseline: a
+// HashMap lookup by opcode byte, then a match on the mnemonic string
+struct LegacyOpCode {
+    mnemonic: String,
+}
+
+fn legacy_map() -> HashMap<u8, LegacyOpCode> {
+    CPU_OPS_CODES
+        .iter()
+        // "ASL A" was plain "ASL" in accumulator mode
+        .map(|op| {
+            let mnemonic = op.instruction.to_string().split(' ').next().unwrap().to_string();
+            (op.code, LegacyOpCode { mnemonic })
+        })
+        .collect()
+}
+
+// The old step formatted its panic message on every lookup, that's part of
+// what it cost
+#[allow(clippy::expect_fun_call)]
+fn legacy_decode(map: &HashMap<u8, LegacyOpCode>, code: u8) -> u8 {
+    let opcode = map.get(&code).expect(&format!("OpCode {:x} is not recognized", code));
+    match opcode.mnemonic.as_str() {
+        "LDA" => 0,
+        "LAX" => 1,
+        "LAS" => 2,
+        "LDX" => 3,
+        "LDY" => 4,
+        "STA" => 5,
+        "STX" => 6,
+        "SAX" => 7,
+        "STY" => 8,
+        "TAX" => 9,
+        "TXA" => 10,
+        "TAY" => 11,
+        "TYA" => 12,
+        "INX" => 13,
+        "DEX" => 14,
+        "INY" => 15,
+        "DEY" => 16,
+        "ASL" => 17,
+        "LSR" => 18,
+        "ROL" => 19,
+        "ROR" => 20,
+        "AND" => 21,
+        "ORA" => 22,
+        "EOR" => 23,
+        "BIT" => 24,
+        "SLO" => 25,
+        "RLA" => 26,
+        "SRE" => 27,
+        "RRA" => 28,
+        "CMP" => 29,
+        "CPX" => 30,
+        "CPY" => 31,
+        "SEC" => 32,
+        "SED" => 33,
+        "SEI" => 34,
+        "CLC" => 35,
+        "CLD" => 36,
+        "CLI" => 37,
+        "CLV" => 38,
+        "ADC" => 39,
+        "SBC" => 40,
+        "BNE" => 41,
+        "BEQ" => 42,
+        "BCC" => 43,
+        "BCS" => 44,
+        "BMI" => 45,
+        "BPL" => 46,
+        "BVC" => 47,
+        "BVS" => 48,
+        "JMP" => 49,
+        "JSR" => 50,
+        "RTS" => 51,
+        "PHA" => 52,
+        "PLA" => 53,
+        "TXS" => 54,
+        "TSX" => 55,
+        "PHP" => 56,
+        "PLP" => 57,
+        "RTI" => 58,
+        "INC" => 59,
+        "DEC" => 60,
+        "DCP" => 61,
+        "ISB" => 62,
+        "AHX" => 63,
+        "SHY" => 64,
+        "SHX" => 65,
+        "NOP" => 66,
+        "BRK" => 67,
+        // KIL and the unstable opcodes, which nestest never runs
+        _ => u8::MAX,
+    }
+}
+
+fn table_decode(code: u8) -> u8 {
+    let opcode = OPCODE_TABLE[code as usize].unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+    opcode.instruction as u8
+}
+
+fn report(name: &str, instructions: usize, start: Instant) {
+    let elapsed = start.elapsed().as_secs_f64();
+    println!(
+        "{:<24} {} instructions in {:.3}s: {:.2} million per second",
+        name,
+        instructions,
+        elapsed,
+        instructions as f64 / elapsed / 1_000_000.0
+    );
+}
+
+fn main() {
+    let bytes = fs::read("roms/nestest.nes").unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+
+    let mut image = vec![0; 0x10000];
+    image[0x8000..0xC000].copy_from_slice(&rom.prg_rom);
+    image[0xC000..].copy_from_slice(&rom.prg_rom);
+    let new_cpu = || {
+        let mut cpu = CPU::new(FlatBus {
+            mem: image.clone(),
+            cycles: 0,
+        });
+        cpu.program_counter = 0xC000;
+        cpu
+    };
+    let instructions = ROUNDS * NESTEST_INSTRUCTIONS;
+
+    // The whole CPU with the table decoder
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut cpu = new_cpu();
+        for _ in 0..NESTEST_INSTRUCTIONS {
+            cpu.step(|_| {}).unwrap();
+        }
+    }
+    report("CPU::step", instructions, start);
+
+    // Decoding alone, old against new, over the opcodes nestest runs
+    let mut cpu = new_cpu();
+    let mut codes = Vec::with_capacity(NESTEST_INSTRUCTIONS);
+    for _ in 0..NESTEST_INSTRUCTIONS {
+        cpu.step(|cpu| codes.push(cpu.bus.mem[cpu.program_counter as usize])).unwrap();
+    }
+
+    let map = legacy_map();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &code in &codes {
+            black_box(legacy_decode(&map, black_box(code)));
+        }
+    }
+    report("HashMap + string match", instructions, start);
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for &code in &codes {
+            black_box(table_decode(black_box(code)));
+        }
+    }
+    report("table + enum", instructions, start);
+}
@@ -0,0 +1,387 @@
+// The sound channels and the units they share. Timers count CPU cycles,
+// envelopes, sweeps and counters are clocked by the frame counter
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16,
+    28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// In CPU cycles
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_NOISE_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+#[derive(Default)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub halt: bool,
+    pub value: u8,
+}
+
+impl LengthCounter {
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[(index >> 3) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    pub looping: bool,
+    pub constant: bool,
+    pub volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // The low 6 bits of $4000, $4004 and $400C
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+pub struct Pulse {
+    // Pulse 1 negates in ones' complement, pulse 2 in two's
+    ones_complement: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = self.period & 0x0700 | data as u16,
+            _ => {
+                self.period = self.period & 0x00FF | ((data & 0b111) as u16) << 8;
+                self.length.load(data);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // The sweep unit mutes the channel even when it's disabled
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length.value == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Triangle {
+    pub length: LengthCounter,
+    step: u8,
+    period: u16,
+    timer: u16,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+}
+
+impl Triangle {
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0b1000_0000 != 0;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = self.period & 0x0700 | data as u16,
+            _ => {
+                self.period = self.period & 0x00FF | ((data & 0b111) as u16) << 8;
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // Periods this short are above hearing and only pop, the
+            // sequencer holds its level instead
+            if self.length.value > 0 && self.linear_counter > 0 && self.period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        // The control flag doubles as the length counter halt
+        if !self.length.halt {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+pub struct Noise {
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    short_mode: bool,
+    periods: &'static [u16; 16],
+    period_index: u8,
+    timer: u16,
+    shift: u16,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            short_mode: false,
+            periods: &NOISE_PERIODS,
+            period_index: 0,
+            timer: 0,
+            shift: 1,
+        }
+    }
+}
+
+impl Noise {
+    pub fn set_pal(&mut self, pal: bool) {
+        self.periods = if pal { &PAL_NOISE_PERIODS } else { &NOISE_PERIODS };
+    }
+
+    pub fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period_index = data & 0x0F;
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Every CPU cycle, the period table is in CPU cycles too
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.periods[self.period_index as usize] - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length.value == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn length_counter_needs_the_channel_enabled() {
+        let mut length = LengthCounter::default();
+        length.load(0b0000_1000);
+        assert_eq!(length.value, 0);
+
+        length.set_enabled(true);
+        length.load(0b0000_1000);
+        assert_eq!(length.value, 254);
+        length.clock();
+        assert_eq!(length.value, 253);
+        length.set_enabled(false);
+        assert_eq!(length.value, 0);
+    }
+
+    #[test]
+    fn envelope_decays_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0b0010_0000);
+        envelope.start = true;
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+    }
+
+    #[test]
+    fn sweep_negates_differently_on_each_pulse() {
+        let mut pulse1 = Pulse::new(true);
+        let mut pulse2 = Pulse::new(false);
+        for pulse in [&mut pulse1, &mut pulse2] {
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x01);
+            pulse.write(1, 0b1000_1001);
+        }
+        assert_eq!(pulse1.sweep_target(), 0x100 - 0x80 - 1);
+        assert_eq!(pulse2.sweep_target(), 0x100 - 0x80);
+    }
+
+    #[test]
+    fn pulse_is_muted_by_low_periods() {
+        let mut pulse = Pulse::new(false);
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0b1011_1111);
+        pulse.write(2, 0x07);
+        pulse.write(3, 0x08);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 0);
+
+        pulse.write(2, 0x08);
+        pulse.step = 1;
+        assert_eq!(pulse.output(), 15);
+    }
+}
@@ -0,0 +1,369 @@
+pub mod channels;
+pub mod wav;
+
+use crate::logging::verbose;
+use crate::region::Region;
+
+use channels::{Noise, Pulse, Triangle};
+
+pub const NTSC_CPU_CLOCK: f64 = 1_789_773.0;
+
+// Frame counter steps in CPU cycles since the sequence started
+struct FrameSteps {
+    quarter_1: u32,
+    half_1: u32,
+    quarter_3: u32,
+    four_step_last: u32,
+    five_step_last: u32,
+}
+
+const NTSC_STEPS: FrameSteps = FrameSteps {
+    quarter_1: 7457,
+    half_1: 14913,
+    quarter_3: 22371,
+    four_step_last: 29829,
+    five_step_last: 37281,
+};
+
+const PAL_STEPS: FrameSteps = FrameSteps {
+    quarter_1: 8313,
+    half_1: 16627,
+    quarter_3: 24939,
+    four_step_last: 33253,
+    five_step_last: 41565,
+};
+
+// Corner of the high-pass filter between the APU and the audio out
+const HIGH_PASS_HZ: f32 = 90.0;
+
+// Averages the mixer output over each sample period, which is enough of a
+// low-pass to keep the pulses from aliasing badly
+struct Sampler {
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    countdown: f64,
+    sum: f32,
+    count: u32,
+    high_pass: f32,
+    last_input: f32,
+    last_output: f32,
+    samples: Vec<f32>,
+}
+
+impl Sampler {
+    fn new(sample_rate: u32, cpu_clock: f64) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * HIGH_PASS_HZ);
+        let dt = 1.0 / sample_rate as f32;
+        Sampler {
+            sample_rate,
+            cycles_per_sample: cpu_clock / sample_rate as f64,
+            countdown: cpu_clock / sample_rate as f64,
+            sum: 0.0,
+            count: 0,
+            high_pass: rc / (rc + dt),
+            last_input: 0.0,
+            last_output: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, level: f32) {
+        self.sum += level;
+        self.count += 1;
+        self.countdown -= 1.0;
+        if self.countdown > 0.0 {
+            return;
+        }
+        self.countdown += self.cycles_per_sample;
+
+        let input = self.sum / self.count as f32;
+        self.sum = 0.0;
+        self.count = 0;
+        self.last_output = self.high_pass * (self.last_output + input - self.last_input);
+        self.last_input = input;
+        self.samples.push(self.last_output);
+    }
+}
+
+// $4000-$4017 without the DMC's sample playback, only its output level
+// from $4011 reaches the mixer
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    dmc_level: u8,
+
+    region: Region,
+    steps: &'static FrameSteps,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    odd_cycle: bool,
+    sampler: Option<Sampler>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Apu::new()
+    }
+}
+
+impl Apu {
+    // Without a sample rate nothing is recorded
+    pub fn new() -> Self {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc_level: 0,
+            region: Region::Ntsc,
+            steps: &NTSC_STEPS,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sampler: None,
+        }
+    }
+
+    pub fn with_sample_rate(sample_rate: u32) -> Self {
+        let mut apu = Apu::new();
+        apu.sampler = Some(Sampler::new(sample_rate, NTSC_CPU_CLOCK));
+        apu
+    }
+
+    // Dendy keeps the NTSC tables, but its samples still come at its own
+    // CPU clock
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.steps = if region.pal_apu() { &PAL_STEPS } else { &NTSC_STEPS };
+        self.noise.set_pal(region.pal_apu());
+        if let Some(sampler) = &mut self.sampler {
+            *sampler = Sampler::new(sampler.sample_rate, region.cpu_clock());
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4011 => self.dmc_level = data & 0b0111_1111,
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+            }
+            0x4017 => {
+                self.five_step = data & 0b1000_0000 != 0;
+                self.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {
+                verbose!(target: "apu", "Ignoring write to {:04X} = {:02X}", addr, data);
+            }
+        }
+    }
+
+    // $4015, reading it acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse1.length.value > 0) as u8
+            | ((self.pulse2.length.value > 0) as u8) << 1
+            | ((self.triangle.length.value > 0) as u8) << 2
+            | ((self.noise.length.value > 0) as u8) << 3
+            | (self.frame_irq as u8) << 6
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        self.frame_cycle += 1;
+        let steps = self.steps;
+        let last = if self.five_step { steps.five_step_last } else { steps.four_step_last };
+        match self.frame_cycle {
+            cycle if cycle == steps.quarter_1 || cycle == steps.quarter_3 => self.clock_quarter_frame(),
+            cycle if cycle == steps.half_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            cycle if cycle == last => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.frame_irq |= !self.five_step && !self.irq_inhibit;
+            }
+            cycle if cycle > last => self.frame_cycle = 0,
+            _ => {}
+        }
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        if self.sampler.is_some() {
+            let level = self.output();
+            if let Some(sampler) = &mut self.sampler {
+                sampler.push(level);
+            }
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // The mixer's nonlinear DAC approximations, 0.0 to about 1.0
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse + 100.0) };
+
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc_level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+
+        pulse_out + tnd_out
+    }
+
+    // Samples since the last call, centered around 0
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.sampler
+            .as_mut()
+            .map_or_else(Vec::new, |sampler| std::mem::take(&mut sampler.samples))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status_shows_running_length_counters() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0000_0101);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0b0000_0101);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn four_step_sequence_raises_the_frame_irq() {
+        let mut apu = Apu::new();
+        for _ in 0..NTSC_STEPS.four_step_last - 1 {
+            apu.tick(1);
+        }
+        assert!(!apu.frame_irq());
+        apu.tick(1);
+        assert!(apu.frame_irq());
+        assert_eq!(apu.read_status() & 0x40, 0x40);
+        assert!(!apu.frame_irq());
+
+        apu.write_register(0x4017, 0b0100_0000);
+        for _ in 0..NTSC_STEPS.four_step_last {
+            apu.tick(1);
+        }
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn length_counters_run_out_on_half_frames() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        // Length index 3 loads 2
+        apu.write_register(0x4003, 0b0001_1000);
+        for _ in 0..NTSC_STEPS.half_1 {
+            apu.tick(1);
+        }
+        assert_eq!(apu.read_status() & 1, 1);
+        for _ in NTSC_STEPS.half_1..NTSC_STEPS.four_step_last {
+            apu.tick(1);
+        }
+        assert_eq!(apu.read_status() & 1, 0);
+    }
+
+    #[test]
+    fn pal_frame_counter_is_slower() {
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        for _ in 0..NTSC_STEPS.four_step_last {
+            apu.tick(1);
+        }
+        assert!(!apu.frame_irq());
+        for _ in NTSC_STEPS.four_step_last..PAL_STEPS.four_step_last {
+            apu.tick(1);
+        }
+        assert!(apu.frame_irq());
+    }
+
+    #[test]
+    fn samples_a_square_wave() {
+        let mut apu = Apu::with_sample_rate(44100);
+        apu.write_register(0x4015, 0b0000_0001);
+        // 50% duty at constant volume 15, period 253 is about 440 Hz
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0b1111_1000);
+        for _ in 0..NTSC_CPU_CLOCK as usize / 10 {
+            apu.tick(1);
+        }
+
+        let samples = apu.take_samples();
+        assert!((4400..=4420).contains(&samples.len()));
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((42..=46).contains(&crossings), "{} crossings", crossings);
+        assert!(apu.take_samples().is_empty());
+    }
+}
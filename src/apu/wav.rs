@@ -0,0 +1,45 @@
+use std::io::{self, Write};
+
+/// Writes `samples` (-1.0 to 1.0) as a mono 16-bit PCM WAV file.
+pub fn write_wav<W: Write>(mut out: W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?; // bytes per frame
+    out.write_all(&16u16.to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_a_riff_header() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 44100, &[0.0, 1.0, -2.0]).unwrap();
+
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 36 + 6);
+        assert_eq!(u32::from_le_bytes(out[24..28].try_into().unwrap()), 44100);
+        assert_eq!(&out[36..40], b"data");
+        assert_eq!(&out[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nes_emulator::cartridge::Rom;
+use nes_emulator::fds::{Fds, FdsImage};
+use nes_emulator::headless::{Headless, InputScript, parse_entry, run_headless};
+use nes_emulator::joypad::JoypadButton;
+use nes_emulator::movie::{Movie, MovieFrame, play_movie};
+use nes_emulator::region::Region;
+use nes_emulator::render::frame::Frame;
+
+const USAGE: &str = "\
+Usage: headless ROM [options]
+
+Runs ROM without a window and prints the hash of every frame. ROM can be a
+.zip or .gz file, and an .ips, .ups or .bps patch with the same name as ROM
+is applied first. ROM can also be an .fds disk image, writes to it are kept
+in ROM.ips, or ROM.ENTRY.ips for an image in a zip file.
+
+Options:
+  --entry NAME        ROM to load from a zip file with several (default the
+                      first)
+  --frames N          frames to run (default 60)
+  --input FRAME:KEYS  hold KEYS on controller 1 from FRAME on, e.g. 60:START
+                      or 90:A+RIGHT, an empty KEYS releases everything
+  --script FILE       read --input entries from FILE, one per line
+  --screenshot FILE   save the last frame, .png or .ppm
+  --record FILE       save the run as an FM2 movie
+  --play FILE         play an FM2 movie instead of --input, warning on the
+                      first frame that renders differently than recorded
+  --region NAME       ntsc, pal or dendy instead of what the header or the
+                      game database says
+  --bios FILE         the FDS BIOS, needed for .fds images
+  --swap FRAME        eject the disk at FRAME and insert its next side a
+                      second later";
+
+struct Args {
+    rom: PathBuf,
+    entry: Option<String>,
+    frames: usize,
+    input: InputScript,
+    screenshot: Option<PathBuf>,
+    record: Option<PathBuf>,
+    play: Option<PathBuf>,
+    region: Option<Region>,
+    bios: Option<PathBuf>,
+    swaps: Vec<usize>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut rom = None;
+    let mut entry = None;
+    let mut frames = 60;
+    let mut input = InputScript::new();
+    let mut screenshot = None;
+    let mut record = None;
+    let mut play = None;
+    let mut region = None;
+    let mut bios = None;
+    let mut swaps = Vec::new();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--entry" => entry = Some(value()?),
+            "--frames" => {
+                let value = value()?;
+                frames = value
+                    .parse()
+                    .map_err(|_| format!("bad frame count {:?}", value))?;
+            }
+            "--input" => {
+                let (frame, buttons) = parse_entry(&value()?)?;
+                input.push(frame, buttons);
+            }
+            "--script" => {
+                let path = value()?;
+                let text = fs::read_to_string(&path).map_err(|err| format!("{}: {}", path, err))?;
+                for (frame, buttons) in InputScript::parse(&text)
+                    .map_err(|err| format!("{}: {}", path, err))?
+                    .entries()
+                {
+                    input.push(*frame, *buttons);
+                }
+            }
+            "--screenshot" => screenshot = Some(PathBuf::from(value()?)),
+            "--record" => record = Some(PathBuf::from(value()?)),
+            "--play" => play = Some(PathBuf::from(value()?)),
+            "--region" => {
+                let value = value()?;
+                region = Some(Region::from_name(&value).ok_or_else(|| format!("unknown region {:?}", value))?);
+            }
+            "--bios" => bios = Some(PathBuf::from(value()?)),
+            "--swap" => {
+                let value = value()?;
+                swaps.push(value.parse().map_err(|_| format!("bad frame number {:?}", value))?);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+
+    Ok(Args {
+        rom: rom.ok_or("no ROM given")?,
+        entry,
+        frames,
+        input,
+        screenshot,
+        record,
+        play,
+        region,
+        bios,
+        swaps,
+    })
+}
+
+fn save_screenshot(frame: &Frame, path: &Path) -> Result<(), String> {
+    let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let out = BufWriter::new(file);
+    let result = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => frame.write_png(out),
+        Some("ppm") => frame.write_ppm(out),
+        _ => return Err(format!("{}: screenshots are .png or .ppm", path.display())),
+    };
+    result.map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn play(rom: Rom, path: &Path) -> Result<Frame, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let movie = Movie::parse_fm2(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    let mut last = Frame::new();
+    let desync = play_movie(rom, &movie, |number, frame| {
+        println!("{:>6} {:016x}", number, frame.hash());
+        last = frame.clone();
+    })
+    .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+    if let Some(desync) = desync {
+        eprintln!(
+            "warning: movie desynced at frame {}, rendered {:016x} but {:016x} was recorded",
+            desync.frame, desync.actual, desync.expected
+        );
+    }
+    Ok(last)
+}
+
+fn run_disk(args: &Args) -> Result<Frame, String> {
+    if args.record.is_some() || args.play.is_some() {
+        return Err("Movies of FDS games aren't supported".to_string());
+    }
+    let bios_path = args.bios.as_ref().ok_or("FDS images need --bios FILE")?;
+    let bios = fs::read(bios_path).map_err(|err| format!("{}: {}", bios_path.display(), err))?;
+    let image = FdsImage::from_path_entry(&args.rom, args.entry.as_deref()).map_err(|err| err.to_string())?;
+
+    let mut console = Headless::with_fds(Fds::new(bios, image)?);
+    if let Some(region) = args.region {
+        console.set_region(region);
+    }
+    for number in 0..args.frames {
+        if args.swaps.contains(&number)
+            && let Some(fds) = console.fds()
+        {
+            fds.switch_side();
+        }
+        let frame = console.run_frame(args.input.buttons_at(number), JoypadButton::empty())?;
+        println!("{:>6} {:016x}", number, frame.hash());
+    }
+
+    if let Some(fds) = console.fds() {
+        fds.save_diff().map_err(|err| format!("{}: {}", args.rom.display(), err))?;
+    }
+    Ok(console.into_frame())
+}
+
+fn run() -> Result<(), String> {
+    let args = parse_args()?;
+    if FdsImage::is_disk_image(&args.rom, args.entry.as_deref()) {
+        let frame = run_disk(&args)?;
+        if let Some(path) = &args.screenshot {
+            save_screenshot(&frame, path)?;
+        }
+        return Ok(());
+    }
+
+    let mut rom = Rom::from_path_entry(&args.rom, args.entry.as_deref()).map_err(|err| err.to_string())?;
+    if let Some(region) = args.region {
+        rom.header.timing = region.timing();
+    }
+
+    let frame = match &args.play {
+        Some(path) => play(rom, path)?,
+        None => {
+            let name = args.rom.file_stem().unwrap_or_default().to_string_lossy();
+            let mut movie = args.record.is_some().then(|| Movie::new(&name, &rom));
+            let frame = run_headless(rom, args.frames, &args.input, |number, frame| {
+                println!("{:>6} {:016x}", number, frame.hash());
+                if let Some(movie) = &mut movie {
+                    let input = MovieFrame::new(args.input.buttons_at(number), JoypadButton::empty());
+                    movie.record(input, frame);
+                }
+            })?;
+
+            if let Some(path) = &args.record
+                && let Some(movie) = &movie
+            {
+                fs::write(path, movie.to_fm2()).map_err(|err| format!("{}: {}", path.display(), err))?;
+            }
+            frame
+        }
+    };
+
+    if let Some(path) = args.screenshot {
+        save_screenshot(&frame, &path)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nes_emulator::apu::wav::write_wav;
+use nes_emulator::cartridge::archive;
+use nes_emulator::nsf::{Nsf, NsfPlayer};
+
+const USAGE: &str = "\
+Usage: nsfplay NSF [options]
+
+Prints the NSF's header, or plays tracks into WAV files with --wav. NSF can
+be a .zip or .gz file.
+
+Options:
+  --track N     track to play, from 1 (default the NSF's starting track)
+  --all         play every track, --wav is then a directory that gets
+                one NN.wav file per track
+  --seconds S   how long to play each track (default 120)
+  --rate HZ     sample rate (default 44100)
+  --wav PATH    where to write the audio";
+
+struct Args {
+    nsf: PathBuf,
+    track: Option<u8>,
+    all: bool,
+    seconds: f64,
+    rate: u32,
+    wav: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let mut nsf = None;
+    let mut track = None;
+    let mut all = false;
+    let mut seconds = 120.0;
+    let mut rate = 44100;
+    let mut wav = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--track" => {
+                let value = value()?;
+                track = Some(value.parse().map_err(|_| format!("bad track {:?}", value))?);
+            }
+            "--all" => all = true,
+            "--seconds" => {
+                let value = value()?;
+                seconds = value.parse().map_err(|_| format!("bad length {:?}", value))?;
+            }
+            "--rate" => {
+                let value = value()?;
+                rate = value.parse().map_err(|_| format!("bad sample rate {:?}", value))?;
+            }
+            "--wav" => wav = Some(PathBuf::from(value()?)),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if nsf.is_none() && !arg.starts_with("--") => nsf = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {:?}", arg)),
+        }
+    }
+
+    Ok(Args {
+        nsf: nsf.ok_or("no NSF given")?,
+        track,
+        all,
+        seconds,
+        rate,
+        wav,
+    })
+}
+
+fn load(path: &Path) -> Result<Nsf, String> {
+    let raw = if archive::is_archive(path) {
+        archive::read(path, None).map_err(|err| err.to_string())?
+    } else {
+        fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?
+    };
+    Nsf::new(&raw).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+fn export(player: &mut NsfPlayer, track: u8, seconds: f64, path: &Path) -> Result<(), String> {
+    player.start_track(track)?;
+    let samples = player.render(seconds)?;
+
+    let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    write_wav(BufWriter::new(file), player.sample_rate(), &samples)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    println!("track {:>3} -> {}", track, path.display());
+    Ok(())
+}
+
+fn run() -> Result<(), String> {
+    let args = parse_args()?;
+    let nsf = load(&args.nsf)?;
+    let header = nsf.header.clone();
+
+    println!("{} - {} ({})", header.title, header.artist, header.copyright);
+    println!(
+        "{} tracks, starting at {}, {:?}, load {:04X} init {:04X} play {:04X}",
+        header.song_count, header.starting_song, header.timing, header.load_addr, header.init_addr, header.play_addr
+    );
+    if !header.expansion.is_empty() {
+        eprintln!("warning: {:?} audio is not emulated", header.expansion);
+    }
+
+    let Some(wav) = args.wav else {
+        return Ok(());
+    };
+    let mut player = NsfPlayer::new(nsf, args.rate)?;
+    if args.all {
+        fs::create_dir_all(&wav).map_err(|err| format!("{}: {}", wav.display(), err))?;
+        for track in 1..=header.song_count {
+            export(&mut player, track, args.seconds, &wav.join(format!("{:02}.wav", track)))?;
+        }
+        Ok(())
+    } else {
+        let track = args.track.unwrap_or(header.starting_song);
+        export(&mut player, track, args.seconds, &wav)
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(1);
+    }
+}
@@ -1,8 +1,14 @@
+use crate::apu::Apu;
 use crate::cartridge::Mirroring;
 use crate::cartridge::Rom;
+use crate::cartridge::header::{RomHeader, TRAINER_SIZE};
 use crate::cartridge::mock_rom;
 use crate::cpu::Mem;
+use crate::fds::{self, Fds};
+use crate::joypad::Joypad;
+use crate::logging::verbose;
 use crate::ppu::*;
+use crate::region::Region;
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -35,17 +41,28 @@ use crate::ppu::*;
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const _PPU_REGISTERS: u16 = 0x2000;
-const PPU_REGISTERS_MIRRORS_END: u16 = 0x401F;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_IO_REGISTERS: u16 = 0x4000;
+const APU_IO_REGISTERS_END: u16 = 0x401F;
 const EXPANSION_END: u16 = 0x5FFF;
 const SAVE_RAM_END: u16 = 0x7FFF;
+const TRAINER: u16 = 0x7000;
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     prg_rom: Vec<u8>,
     ppu: NesPPU,
+    apu: Apu,
     expansion_rom: [u8; 8188],
     save_ram: [u8; 8192],
+    joypad1: Joypad,
+    joypad2: Joypad,
+    // Takes over $4020-$4033 and $6000-$FFFF when a disk system is plugged in
+    fds: Option<Fds>,
 
+    region: Region,
+    // Fifths of a dot left over on PAL
+    dot_fraction: u32,
     cycles: usize,
     gameloop_callback: Box<dyn FnMut(&NesPPU) + 'call>,
 }
@@ -54,6 +71,26 @@ pub trait BusOP: Mem {
     fn tick(&mut self, cycles: u8);
     fn poll_nmi_status(&mut self) -> Option<u8>;
     fn cycles(&mut self) -> usize;
+
+    // The IRQ line is level triggered, it stays up until the source is
+    // acknowledged
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Where `addr` currently lands in PRG ROM, used for bank-aware symbols
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+
+    fn ppu_timing(&self) -> Option<PpuTiming> {
+        None
+    }
+
+    // A read without side effects, for showing operand values in traces
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.mem_read(addr)
+    }
 }
 
 impl<'a> BusOP for Bus<'a> {
@@ -61,20 +98,52 @@ impl<'a> BusOP for Bus<'a> {
         self.cycles
     }
     fn tick(&mut self, cycles: u8) {
-        self.cycles += cycles as usize;
+        Bus::tick(self, cycles);
+    }
 
-        let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
-        let nmi_after = self.ppu.nmi_interrupt.is_some();
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        self.ppu.nmi_interrupt.take()
+    }
 
-        if !nmi_before && nmi_after {
-            // (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
-            (self.gameloop_callback)(&self.ppu);
+    fn irq_pending(&self) -> bool {
+        self.apu.frame_irq() || self.fds.as_ref().is_some_and(Fds::irq)
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg_rom.is_empty() {
+            return None;
         }
+        Some((addr - 0x8000) as usize % self.prg_rom.len())
     }
 
-    fn poll_nmi_status(&mut self) -> Option<u8> {
-        self.ppu.nmi_interrupt
+    fn ppu_timing(&self) -> Option<PpuTiming> {
+        Some(self.ppu.timing())
+    }
+
+    // Reading $2002 clears vblank and the w latch and $2007 moves the VRAM
+    // address, the other PPU registers can be read as they are. The APU and
+    // controller registers show as open bus, the way Nintendulator's
+    // nestest log has them
+    fn peek(&mut self, addr: u16) -> u8 {
+        if let Some(data) = self.fds.as_ref().and_then(|fds| fds.peek(addr)) {
+            return data;
+        }
+        match addr {
+            0x2002 => self.ppu.direct_read_status(),
+            0x2007 => self.ppu.peek_data(),
+            0x2008..=PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b0010_0000_0000_0111),
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => 0xFF,
+            _ => self.mem_read(addr),
+        }
+    }
+}
+
+// Cartridges without CHR ROM have RAM there instead
+fn new_ppu(chr_rom: Vec<u8>, header: &RomHeader) -> NesPPU {
+    if chr_rom.is_empty() {
+        NesPPU::with_chr_ram(header.chr_ram_size + header.chr_nvram_size, header.mirroring.clone())
+    } else {
+        NesPPU::new(chr_rom, header.mirroring.clone())
     }
 }
 
@@ -87,7 +156,13 @@ impl<'a> Bus<'a> {
             prg_rom: [0; 0x4000].to_vec(),
             expansion_rom: [0; 8188],
             save_ram: [0; 8192],
-            ppu: ppu,
+            ppu,
+            apu: Apu::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            fds: None,
+            region: Region::Ntsc,
+            dot_fraction: 0,
             cycles: 0,
             gameloop_callback: Box::new(|_: &NesPPU| {}),
         }
@@ -95,13 +170,19 @@ impl<'a> Bus<'a> {
 
     pub fn mock_bus(code: Vec<u8>) -> Self {
         let rom = mock_rom(code);
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
+        let ppu = new_ppu(rom.chr_rom, &rom.header);
         Bus {
             cpu_vram: [0; 2048],
             prg_rom: rom.prg_rom,
             expansion_rom: [0; 8188],
             save_ram: [0; 8192],
-            ppu: ppu,
+            ppu,
+            apu: Apu::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            fds: None,
+            region: Region::Ntsc,
+            dot_fraction: 0,
             cycles: 0,
             gameloop_callback: Box::new(|_: &NesPPU| {}),
         }
@@ -111,22 +192,68 @@ impl<'a> Bus<'a> {
     where
         F: FnMut(&NesPPU) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom, rom.screen_mirroring);
-        Bus {
+        let ppu = new_ppu(rom.chr_rom, &rom.header);
+        let region = Region::from_timing(rom.header.timing);
+
+        let mut save_ram = [0; 8192];
+        if let Some(trainer) = &rom.trainer {
+            // Only 512 bytes fit where the trainer goes, the rest is dropped
+            let trainer = &trainer[..trainer.len().min(TRAINER_SIZE)];
+            let start = (TRAINER - 0x6000) as usize;
+            save_ram[start..start + trainer.len()].copy_from_slice(trainer);
+        }
+
+        let mut bus = Bus {
             cpu_vram: [0; 2048],
             prg_rom: rom.prg_rom,
             expansion_rom: [0; 8188],
+            save_ram,
+            ppu,
+            apu: Apu::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            fds: None,
+            region: Region::Ntsc,
+            dot_fraction: 0,
+            cycles: 0,
+            gameloop_callback: Box::from(gameloop_callback),
+        };
+        bus.set_region(region);
+        bus
+    }
+
+    // The disk system in place of a cartridge, with CHR RAM for the PPU
+    pub fn with_fds<'call, F>(fds: Fds, gameloop_callback: F) -> Bus<'call>
+    where
+        F: FnMut(&NesPPU) + 'call,
+    {
+        Bus {
+            cpu_vram: [0; 2048],
+            prg_rom: Vec::new(),
+            expansion_rom: [0; 8188],
             save_ram: [0; 8192],
-            ppu: ppu,
+            ppu: NesPPU::with_chr_ram(fds::CHR_RAM_SIZE, fds.mirroring()),
+            apu: Apu::new(),
+            joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            fds: Some(fds),
+            region: Region::Ntsc,
+            dot_fraction: 0,
             cycles: 0,
             gameloop_callback: Box::from(gameloop_callback),
         }
     }
+
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
 
+        self.apu.tick(cycles);
+        if let Some(fds) = &mut self.fds {
+            fds.tick(cycles);
+        }
         let nmi_before = self.ppu.nmi_interrupt.is_some();
-        self.ppu.tick(cycles * 3);
+        let dots = self.ppu_dots(cycles);
+        self.ppu.tick(dots);
         let nmi_after = self.ppu.nmi_interrupt.is_some();
 
         if !nmi_before && nmi_after {
@@ -135,11 +262,49 @@ impl<'a> Bus<'a> {
         }
     }
 
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.dot_fraction = 0;
+        self.ppu.region = region;
+        self.apu.set_region(region);
+    }
+
+    fn ppu_dots(&mut self, cycles: u8) -> u8 {
+        let (dots, per_cycles) = self.region.dots_per_cycle();
+        let dots = self.dot_fraction + cycles as u32 * dots;
+        self.dot_fraction = dots % per_cycles;
+        (dots / per_cycles) as u8
+    }
+
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    pub fn apu(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn fds(&mut self) -> Option<&mut Fds> {
+        self.fds.as_mut()
+    }
+
+    pub fn joypad1(&mut self) -> &mut Joypad {
+        &mut self.joypad1
+    }
+
+    pub fn joypad2(&mut self) -> &mut Joypad {
+        &mut self.joypad2
+    }
+
     fn read_prg_rom(&mut self, mut addr: u16) -> u8 {
         addr -= 0x8000;
         if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
             // Mirror if needed
-            addr = addr % 0x4000;
+            addr %= 0x4000;
         }
         self.prg_rom[addr as usize]
     }
@@ -148,10 +313,13 @@ impl<'a> Bus<'a> {
 
 impl<'a> Mem for Bus<'a> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        if let Some(data) = self.fds.as_mut().and_then(|fds| fds.read(addr)) {
+            return data;
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
-                println!("Reading: {:4X} -> {:4X}", addr, mirror_down_addr);
+                verbose!(target: "bus", "Reading: {:04X} -> {:04X}", addr, mirror_down_addr);
                 self.cpu_vram[mirror_down_addr as usize]
             }
             0x2000 => self.ppu.read_ctrl(),
@@ -160,7 +328,7 @@ impl<'a> Mem for Bus<'a> {
             0x2003 => self.ppu.read_oam_addr(),
             0x2004 => {
                 let data = self.ppu.read_oam_data();
-                println!("Reading oam data 0x2004(8196): 0x{:02X}", data);
+                verbose!(target: "bus", "Reading oam data 0x2004(8196): 0x{:02X}", data);
                 data
             }
             0x2005 => self.ppu.read_scroll(),
@@ -170,12 +338,20 @@ impl<'a> Mem for Bus<'a> {
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0010_0000_0000_0111;
                 let data = self.mem_read(mirror_down_addr);
-                println!(
+                verbose!(
+                    target: "bus",
                     "Reading: {:04X} -> {:04X}: {:02X}",
                     addr, mirror_down_addr, data
                 );
                 data
             }
+            // The upper bits aren't driven by the controller port and keep
+            // the $40 of the address still on the bus
+            0x4016 => 0x40 | self.joypad1.read(),
+            0x4017 => 0x40 | self.joypad2.read(),
+            0x4015 => self.apu.read_status(),
+            // The other APU registers are write only
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => 0xFF,
             0x8000..=0xFFFF => self.read_prg_rom(addr),
             0x4020..=EXPANSION_END => self.expansion_rom[(addr - 0x4020) as usize],
             0x6000..=SAVE_RAM_END => self.save_ram[(addr - 0x6000) as usize],
@@ -183,10 +359,16 @@ impl<'a> Mem for Bus<'a> {
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(fds) = &mut self.fds
+            && fds.write(addr, data)
+        {
+            self.ppu.mirroring = fds.mirroring();
+            return;
+        }
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
-                println!("Writing: {} -> {}", addr, mirror_down_addr);
+                verbose!(target: "bus", "Writing: {:04X} -> {:04X}", addr, mirror_down_addr);
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
             0x2000 => {
@@ -196,7 +378,8 @@ impl<'a> Mem for Bus<'a> {
                 self.ppu.write_to_mask(data);
             }
             0x2002 => {
-                panic!("Attempt to write to PPU status register!");
+                // Read only, the write goes nowhere
+                verbose!(target: "bus", "Ignoring write to PPU status: {:02X}", data);
             }
             0x2003 => {
                 self.ppu.write_to_oam_addr(data);
@@ -216,14 +399,25 @@ impl<'a> Mem for Bus<'a> {
 
             0x2008..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00100000_00000111;
-                println!(
+                verbose!(
+                    target: "bus",
                     "Writing: {:04X} -> {:04X}: {:02X}",
                     addr, mirror_down_addr, data
                 );
                 self.mem_write(mirror_down_addr, data);
             }
+            0x4016 => {
+                // One strobe line goes to both ports
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => {
+                verbose!(target: "bus", "Ignoring write to {:04X} = {:02X}", addr, data);
+            }
             0x8000..=0xFFFF => {
-                panic!("Attempt to write to Cartridge ROM space");
+                // NROM has no registers to catch writes, so ROM ignores them
+                verbose!(target: "mapper", "Ignoring write to ROM: {:04X} = {:02X}", addr, data);
             }
             0x4020..=EXPANSION_END => {
                 self.expansion_rom[(addr - 0x4020) as usize] = data;
@@ -234,3 +428,67 @@ impl<'a> Mem for Bus<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::header::Timing;
+
+    #[test]
+    fn trainer_is_loaded_at_7000() {
+        let mut rom = mock_rom(vec![0; 0x4000]);
+        rom.trainer = Some((0..=255).chain(0..=255).collect());
+
+        let mut bus = Bus::new(rom, |_| {});
+        assert_eq!(bus.mem_read(0x6FFF), 0);
+        assert_eq!(bus.mem_read(0x7000), 0);
+        assert_eq!(bus.mem_read(0x70FF), 0xFF);
+        assert_eq!(bus.mem_read(0x71FF), 0xFF);
+        assert_eq!(bus.mem_read(0x7200), 0);
+
+        let mut rom = mock_rom(vec![0; 0x4000]);
+        rom.trainer = Some(vec![0xAA; 0x2000]);
+        let mut bus = Bus::new(rom, |_| {});
+        assert_eq!(bus.mem_read(0x71FF), 0xAA);
+        assert_eq!(bus.mem_read(0x7200), 0);
+    }
+
+    #[test]
+    fn frame_irq_is_raised_and_acknowledged_through_4015() {
+        let mut bus = Bus::new(mock_rom(vec![0; 0x4000]), |_| {});
+        bus.mem_write(0x4015, 0b0001);
+        bus.mem_write(0x4003, 0b0000_1000);
+        for _ in 0..30000 {
+            bus.tick(1);
+        }
+        assert!(bus.irq_pending());
+        // The trace logger's view doesn't acknowledge it
+        assert_eq!(bus.peek(0x4015), 0xFF);
+        assert_eq!(bus.mem_read(0x4015), 0b0100_0001);
+        assert!(!bus.irq_pending());
+        assert_eq!(bus.mem_read(0x4015), 0b0000_0001);
+    }
+
+    fn cycles_per_frame(timing: Timing) -> usize {
+        let mut rom = mock_rom(vec![0; 0x4000]);
+        rom.header.timing = timing;
+        let mut bus = Bus::new(rom, |_| {});
+        while bus.ppu().timing().frame < 1 {
+            bus.tick(1);
+        }
+        let start = bus.cycles;
+        while bus.ppu().timing().frame < 2 {
+            bus.tick(1);
+        }
+        bus.cycles - start
+    }
+
+    #[test]
+    fn frame_length_follows_the_region() {
+        // 262 and 312 scanlines of 341 dots, 3.2 dots per cycle on PAL
+        assert_eq!(cycles_per_frame(Timing::Ntsc), 29781);
+        assert_eq!(cycles_per_frame(Timing::Multi), 29781);
+        assert!((33247..=33248).contains(&cycles_per_frame(Timing::Pal)));
+        assert_eq!(cycles_per_frame(Timing::Dendy), 35464);
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+use super::LoadError;
+
+// Files inside an archive that can be loaded, the rest is skipped
+pub const ROM_EXTENSIONS: [&str; 4] = ["nes", "unf", "nsf", "fds"];
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.iter().any(|known| ext.eq_ignore_ascii_case(known)))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    has_extension(path, &["zip", "gz"])
+}
+
+pub fn is_zip(path: &Path) -> bool {
+    has_extension(path, &["zip"])
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<BufReader<File>>, LoadError> {
+    let file = File::open(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+    ZipArchive::new(BufReader::new(file)).map_err(|err| LoadError::Archive(path.to_path_buf(), err.to_string()))
+}
+
+// ROM entries of a zip file in archive order, a gzip file holds just one
+pub fn rom_entries(path: &Path) -> Result<Vec<String>, LoadError> {
+    if !is_zip(path) {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        return Ok(vec![name.to_string()]);
+    }
+
+    let zip = open_zip(path)?;
+    Ok(zip
+        .file_names()
+        .filter(|name| !name.ends_with('/') && has_extension(Path::new(name), &ROM_EXTENSIONS))
+        .map(str::to_string)
+        .collect())
+}
+
+// The zip entry `read` loads for `entry`
+pub fn entry_name(path: &Path, entry: Option<&str>) -> Result<String, LoadError> {
+    match entry {
+        Some(name) => Ok(name.to_string()),
+        None => rom_entries(path)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| LoadError::Archive(path.to_path_buf(), "no .nes, .unf, .nsf or .fds file inside".to_string())),
+    }
+}
+
+/// Decompresses `entry` from a .zip or .gz file, or the first ROM entry
+/// when it's `None`.
+pub fn read(path: &Path, entry: Option<&str>) -> Result<Vec<u8>, LoadError> {
+    let mut raw = Vec::new();
+
+    if !is_zip(path) {
+        let file = File::open(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+        GzDecoder::new(BufReader::new(file))
+            .read_to_end(&mut raw)
+            .map_err(|err| LoadError::Archive(path.to_path_buf(), err.to_string()))?;
+        return Ok(raw);
+    }
+
+    let name = entry_name(path, entry)?;
+    let mut zip = open_zip(path)?;
+    let mut file = zip
+        .by_name(&name)
+        .map_err(|err| LoadError::Archive(path.to_path_buf(), format!("{}: {}", name, err)))?;
+    file.read_to_end(&mut raw)
+        .map_err(|err| LoadError::Archive(path.to_path_buf(), format!("{}: {}", name, err)))?;
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_zip_entries() {
+        let dir = temp_dir("zip");
+        let path = dir.join("games.zip");
+
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        for (name, data) in [("readme.txt", &b"hi"[..]), ("a/first.nes", b"one"), ("second.NES", b"two"), ("disk.fds", b"three")] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+
+        assert_eq!(rom_entries(&path).unwrap(), vec!["a/first.nes", "second.NES", "disk.fds"]);
+        assert_eq!(read(&path, None).unwrap(), b"one");
+        assert_eq!(read(&path, Some("second.NES")).unwrap(), b"two");
+        assert!(matches!(read(&path, Some("missing.nes")), Err(LoadError::Archive(..))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_gzip() {
+        let dir = temp_dir("gz");
+        let path = dir.join("game.nes.gz");
+
+        let mut gz = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        gz.write_all(b"rom bytes").unwrap();
+        gz.finish().unwrap();
+
+        assert!(is_archive(&path));
+        assert_eq!(rom_entries(&path).unwrap(), vec!["game.nes"]);
+        assert_eq!(read(&path, None).unwrap(), b"rom bytes");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use lazy_static::lazy_static;
+
+use super::Mirroring;
+use super::header::{RomHeader, Timing};
+
+lazy_static! {
+    pub static ref BUILTIN_GAMES: GameDb =
+        GameDb::parse(include_str!("gamedb.txt")).expect("gamedb.txt is broken");
+}
+
+// One game from the database, `None` fields keep the header's value
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GameInfo {
+    pub title: String,
+    pub crc32: Option<u32>,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub timing: Option<Timing>,
+    pub expansion_device: Option<u8>,
+}
+
+impl GameInfo {
+    pub fn apply(&self, header: &mut RomHeader) {
+        if let Some(mapper) = self.mapper {
+            header.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper {
+            header.submapper = submapper;
+        }
+        if let Some(mirroring) = &self.mirroring {
+            header.mirroring = mirroring.clone();
+        }
+        if let Some(battery) = self.battery {
+            header.battery = battery;
+        }
+        if let Some(timing) = self.timing {
+            header.timing = timing;
+        }
+        if let Some(device) = self.expansion_device {
+            header.expansion_device = device;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GameDb {
+    games: Vec<GameInfo>,
+}
+
+impl GameDb {
+    // The format is described at the top of gamedb.txt
+    pub fn parse(text: &str) -> Result<GameDb, String> {
+        let mut games: Vec<GameInfo> = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", index + 1, message);
+
+            if let Some(title) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                games.push(GameInfo {
+                    title: title.trim().to_string(),
+                    ..GameInfo::default()
+                });
+                continue;
+            }
+
+            let game = games.last_mut().ok_or_else(|| error("field before the first [title]".to_string()))?;
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| error(format!("expected key = value, got {:?}", line)))?;
+            let bad = || error(format!("bad {} {:?}", key, value));
+
+            match key {
+                "crc32" => game.crc32 = Some(u32::from_str_radix(value, 16).map_err(|_| bad())?),
+                "sha1" => game.sha1 = Some(parse_sha1(value).ok_or_else(bad)?),
+                "mapper" => game.mapper = Some(value.parse().map_err(|_| bad())?),
+                "submapper" => game.submapper = Some(value.parse().map_err(|_| bad())?),
+                "mirroring" => {
+                    game.mirroring = Some(match value {
+                        "horizontal" => Mirroring::HORIZONTAL,
+                        "vertical" => Mirroring::VERTICAL,
+                        "four-screen" => Mirroring::FOUR_SCREEN,
+                        _ => return Err(bad()),
+                    })
+                }
+                "battery" => {
+                    game.battery = Some(match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(bad()),
+                    })
+                }
+                "region" => {
+                    game.timing = Some(match value {
+                        "ntsc" => Timing::Ntsc,
+                        "pal" => Timing::Pal,
+                        "multi" => Timing::Multi,
+                        "dendy" => Timing::Dendy,
+                        _ => return Err(bad()),
+                    })
+                }
+                "input" => game.expansion_device = Some(value.parse().map_err(|_| bad())?),
+                _ => return Err(error(format!("unknown field {:?}", key))),
+            }
+        }
+
+        if let Some(game) = games.iter().find(|game| game.crc32.is_none() && game.sha1.is_none()) {
+            return Err(format!("{} has neither crc32 nor sha1", game.title));
+        }
+        Ok(GameDb { games })
+    }
+
+    // Entries added later win over what's already there
+    pub fn extend(&mut self, other: GameDb) {
+        let mut games = other.games;
+        games.append(&mut self.games);
+        self.games = games;
+    }
+
+    pub fn games(&self) -> &[GameInfo] {
+        &self.games
+    }
+
+    // A game listing both hashes has to match both
+    pub fn lookup(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameInfo> {
+        let mut crc32 = crc32fast::Hasher::new();
+        crc32.update(prg_rom);
+        crc32.update(chr_rom);
+        let crc32 = crc32.finalize();
+
+        let mut sha1 = None;
+        self.games.iter().find(|game| {
+            if game.crc32.is_some_and(|expected| expected != crc32) {
+                return false;
+            }
+            match game.sha1 {
+                Some(expected) => {
+                    let actual = sha1.get_or_insert_with(|| {
+                        let mut hasher = sha1_smol::Sha1::new();
+                        hasher.update(prg_rom);
+                        hasher.update(chr_rom);
+                        hasher.digest().bytes()
+                    });
+                    *actual == expected
+                }
+                None => true,
+            }
+        })
+    }
+}
+
+fn parse_sha1(value: &str) -> Option<[u8; 20]> {
+    if value.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(sha1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_entries() {
+        let db = GameDb::parse(
+            "# comment\n[Some Game]\ncrc32 = 0000ABCD\nmapper = 4\nmirroring = four-screen\nbattery = yes\nregion = pal # PAL only\n\n[Other]\nsha1 = 0123456789ABCDEF0123456789ABCDEF01234567\n",
+        )
+        .unwrap();
+
+        let game = &db.games()[0];
+        assert_eq!(game.title, "Some Game");
+        assert_eq!(game.crc32, Some(0xABCD));
+        assert_eq!(game.mapper, Some(4));
+        assert_eq!(game.mirroring, Some(Mirroring::FOUR_SCREEN));
+        assert_eq!(game.battery, Some(true));
+        assert_eq!(game.timing, Some(Timing::Pal));
+        assert_eq!(game.submapper, None);
+        assert_eq!(db.games()[1].sha1.unwrap()[19], 0x67);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        assert_eq!(GameDb::parse("mapper = 1").unwrap_err(), "line 1: field before the first [title]");
+        assert_eq!(
+            GameDb::parse("[A]\ncrc32 = 1\nmirroring = diagonal").unwrap_err(),
+            "line 3: bad mirroring \"diagonal\""
+        );
+        assert_eq!(GameDb::parse("[A]\nmapper = 1").unwrap_err(), "A has neither crc32 nor sha1");
+    }
+
+    #[test]
+    fn looks_up_by_crc32_and_sha1() {
+        // CRC32 of "123456789" is CBF43926
+        let mut db = GameDb::parse("[By CRC]\ncrc32 = CBF43926\nmapper = 1\n").unwrap();
+        assert_eq!(db.lookup(b"1234", b"56789").unwrap().title, "By CRC");
+        assert!(db.lookup(b"1234", b"5678").is_none());
+
+        db.extend(
+            GameDb::parse("[By SHA-1]\nsha1 = F7C3BC1D808E04732ADF679965CCC34CA7AE3441\nmapper = 2\n").unwrap(),
+        );
+        assert_eq!(db.lookup(b"1234", b"56789").unwrap().title, "By SHA-1");
+    }
+
+    #[test]
+    fn builtin_database_knows_the_bundled_roms() {
+        let bytes = std::fs::read("roms/pacman.nes").unwrap();
+        let game = BUILTIN_GAMES.lookup(&bytes[16..16 + 0x4000], &bytes[16 + 0x4000..]).unwrap();
+        assert_eq!(game.title, "Pac-Man");
+    }
+}
@@ -0,0 +1,283 @@
+use super::Mirroring;
+
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+pub const PRG_ROM_PAGE_SIZE: usize = 16384;
+pub const CHR_ROM_PAGE_SIZE: usize = 8192;
+const PRG_RAM_PAGE_SIZE: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderFormat {
+    INes,
+    Nes20,
+    // Not a header at all, see unif.rs
+    Unif,
+}
+
+// How much of a header that isn't NES 2.0 is trusted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeaderMode {
+    // Every reserved bit has to be clear
+    Strict,
+    // Old dumping tools left their names in bytes 7-15 ("DiskDude!"). Those
+    // bytes are ignored when they can't be a real header
+    Lenient,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    // Runs on either, the emulator picks
+    Multi,
+    Dendy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu: u8, hardware: u8 },
+    Playchoice10,
+    // Byte 13 of NES 2.0 headers, e.g. 3 for the VT01 famiclones
+    Extended(u8),
+}
+
+// Bytes 4-15 of an iNES or NES 2.0 file. Sizes are in bytes, fields that
+// iNES 1.0 can't express get what an iNES 1.0 loader would assume
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_roms: u8,
+    // Default input device from the NES 2.0 list, 1 is standard controllers
+    // and 0 means the header doesn't say
+    pub expansion_device: u8,
+}
+
+impl RomHeader {
+    // iNES 1.0 defaults for a board without a file behind it
+    pub fn new(mapper: u16, mirroring: Mirroring) -> Self {
+        RomHeader {
+            format: HeaderFormat::INes,
+            mapper,
+            submapper: 0,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            prg_ram_size: PRG_RAM_PAGE_SIZE,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring,
+            battery: false,
+            trainer: false,
+            timing: Timing::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            expansion_device: 0,
+        }
+    }
+
+    pub fn parse(raw: &[u8], mode: HeaderMode) -> Result<RomHeader, String> {
+        if raw.len() < HEADER_SIZE {
+            return Err("This is not an iNES file!".to_string());
+        }
+        if raw[..4] != NES_TAG {
+            return Err("Wrong Nes Tag!".to_string());
+        }
+
+        if raw[7] & 0b0000_1100 == 0b0000_1000 {
+            return Ok(RomHeader::parse_nes20(raw));
+        }
+
+        // Anything but zeros here means bytes 7-15 weren't written by an
+        // iNES aware tool
+        let dirty = raw[7] & 0b0000_1100 != 0 || raw[12..16].iter().any(|&byte| byte != 0);
+        if dirty && mode == HeaderMode::Strict {
+            return Err("iNES header has garbage in bytes 7-15!".to_string());
+        }
+        Ok(RomHeader::parse_ines(raw, dirty))
+    }
+
+    fn parse_ines(raw: &[u8], dirty: bool) -> RomHeader {
+        let flags6 = raw[6];
+        let flags7 = if dirty { 0 } else { raw[7] };
+
+        let mut header = RomHeader::new((flags7 & 0xF0 | flags6 >> 4) as u16, mirroring(flags6));
+        header.prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        header.chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        header.battery = flags6 & 0b0000_0010 != 0;
+        header.trainer = flags6 & 0b0000_0100 != 0;
+        header.console_type = console_type(flags7, 0);
+
+        if !dirty {
+            // Zero means 8K, for compatibility with files older than the byte
+            let prg_ram_size = raw[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+            if header.battery {
+                header.prg_ram_size = 0;
+                header.prg_nvram_size = prg_ram_size;
+            } else {
+                header.prg_ram_size = prg_ram_size;
+            }
+            if raw[9] & 1 != 0 {
+                header.timing = Timing::Pal;
+            }
+        } else if header.battery {
+            header.prg_ram_size = 0;
+            header.prg_nvram_size = PRG_RAM_PAGE_SIZE;
+        }
+        if header.chr_rom_size == 0 {
+            header.chr_ram_size = CHR_ROM_PAGE_SIZE;
+        }
+        header
+    }
+
+    fn parse_nes20(raw: &[u8]) -> RomHeader {
+        let flags6 = raw[6];
+        let flags7 = raw[7];
+        let mapper = (raw[8] as u16 & 0x0F) << 8 | (flags7 & 0xF0) as u16 | (flags6 >> 4) as u16;
+
+        let mut header = RomHeader::new(mapper, mirroring(flags6));
+        header.format = HeaderFormat::Nes20;
+        header.submapper = raw[8] >> 4;
+        header.prg_rom_size = rom_size(raw[4], raw[9] & 0x0F, PRG_ROM_PAGE_SIZE);
+        header.chr_rom_size = rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE);
+        header.prg_ram_size = ram_size(raw[10] & 0x0F);
+        header.prg_nvram_size = ram_size(raw[10] >> 4);
+        header.chr_ram_size = ram_size(raw[11] & 0x0F);
+        header.chr_nvram_size = ram_size(raw[11] >> 4);
+        header.battery = flags6 & 0b0000_0010 != 0;
+        header.trainer = flags6 & 0b0000_0100 != 0;
+        header.timing = match raw[12] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::Multi,
+            _ => Timing::Dendy,
+        };
+        header.console_type = console_type(flags7, raw[13]);
+        header.misc_roms = raw[14] & 0b11;
+        header.expansion_device = raw[15] & 0b0011_1111;
+        header
+    }
+}
+
+fn mirroring(flags6: u8) -> Mirroring {
+    match (flags6 & 0b0000_1000 != 0, flags6 & 0b0000_0001 != 0) {
+        (true, _) => Mirroring::FOUR_SCREEN,
+        (false, true) => Mirroring::VERTICAL,
+        (false, false) => Mirroring::HORIZONTAL,
+    }
+}
+
+fn console_type(flags7: u8, byte13: u8) -> ConsoleType {
+    match flags7 & 0b11 {
+        0 => ConsoleType::Nes,
+        1 => ConsoleType::VsSystem {
+            ppu: byte13 & 0x0F,
+            hardware: byte13 >> 4,
+        },
+        2 => ConsoleType::Playchoice10,
+        _ => ConsoleType::Extended(byte13 & 0x0F),
+    }
+}
+
+// With an MSB nibble of $F the LSB is EEEEEEMM, for 2^E * (MM * 2 + 1)
+// bytes. Sizes that overflow can't be in any file and fail to load later
+fn rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0x0F {
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl((lsb >> 2) as u32)
+            .and_then(|size| size.checked_mul(multiplier))
+            .unwrap_or(usize::MAX)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
+}
+
+// A shift count of 0 means none, otherwise 64 << count bytes
+fn ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(bytes: [u8; 12]) -> Vec<u8> {
+        let mut raw = NES_TAG.to_vec();
+        raw.extend(bytes);
+        raw
+    }
+
+    #[test]
+    fn parses_ines() {
+        let raw = header([0x02, 0x00, 0x13, 0x10, 0x02, 0x01, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&raw, HeaderMode::Strict).unwrap();
+
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper, 0x11);
+        assert_eq!(header.prg_rom_size, 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(header.chr_ram_size, 8192);
+        assert_eq!(header.prg_nvram_size, 2 * PRG_RAM_PAGE_SIZE);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.mirroring, Mirroring::VERTICAL);
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn ines_allows_vs_and_playchoice_bits() {
+        let raw = header([0x02, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&raw, HeaderMode::Strict).unwrap();
+        assert_eq!(header.console_type, ConsoleType::VsSystem { ppu: 0, hardware: 0 });
+    }
+
+    #[test]
+    fn cleans_dirty_headers_in_lenient_mode() {
+        let mut raw = NES_TAG.to_vec();
+        raw.extend([0x02, 0x01, 0x41]);
+        raw.extend(b"DiskDude!");
+
+        assert!(RomHeader::parse(&raw, HeaderMode::Strict).is_err());
+        let header = RomHeader::parse(&raw, HeaderMode::Lenient).unwrap();
+        assert_eq!(header.mapper, 4);
+        assert_eq!(header.console_type, ConsoleType::Nes);
+        assert_eq!(header.prg_ram_size, PRG_RAM_PAGE_SIZE);
+    }
+
+    #[test]
+    fn parses_nes20() {
+        // Mapper 0x13A submapper 5, 2 + 256 PRG pages, CHR in exponent
+        // notation: 2^10 * 3
+        let raw = header([0x02, 0x29, 0xA2, 0x3B, 0x51, 0xF1, 0x70, 0x07, 0x03, 0x00, 0x01, 0x02]);
+        let header = RomHeader::parse(&raw, HeaderMode::Strict).unwrap();
+
+        assert_eq!(header.format, HeaderFormat::Nes20);
+        assert_eq!(header.mapper, 0x13A);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.prg_rom_size, 0x102 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(header.chr_rom_size, 1024 * 3);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 8192);
+        assert_eq!(header.chr_ram_size, 8192);
+        assert_eq!(header.chr_nvram_size, 0);
+        assert!(header.battery);
+        assert_eq!(header.mirroring, Mirroring::HORIZONTAL);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(header.console_type, ConsoleType::Extended(0));
+        assert_eq!(header.misc_roms, 1);
+        assert_eq!(header.expansion_device, 2);
+    }
+}
@@ -0,0 +1,336 @@
+pub mod archive;
+pub mod gamedb;
+pub mod header;
+pub mod patch;
+pub mod unif;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use derive_more::Display;
+
+use gamedb::{BUILTIN_GAMES, GameDb};
+use header::{HEADER_SIZE, HeaderMode, RomHeader, TRAINER_SIZE};
+use patch::{PATCH_EXTENSIONS, PatchError};
+
+#[derive(Clone, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Mirroring {
+    VERTICAL,
+    HORIZONTAL,
+    FOUR_SCREEN,
+}
+
+#[derive(Clone)]
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // Loaded to $7000-$71FF on power-on
+    pub trainer: Option<Vec<u8>>,
+    pub header: RomHeader,
+    // From the game database, when the ROM is in it
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Display)]
+pub enum LoadError {
+    #[display("{}: {}", _0.display(), _1)]
+    Io(PathBuf, io::Error),
+
+    #[display("{}: {}", _0.display(), _1)]
+    Archive(PathBuf, String),
+
+    #[display("{}: {}", _0.display(), _1)]
+    Patch(PathBuf, PatchError),
+
+    #[display("{}: {}", _0.display(), _1)]
+    Rom(PathBuf, String),
+}
+
+impl std::error::Error for LoadError {}
+
+pub fn mock_rom(code: Vec<u8>) -> Rom {
+    let mut header = RomHeader::new(0, Mirroring::VERTICAL);
+    header.prg_rom_size = code.len();
+    Rom {
+        prg_rom: code,
+        chr_rom: [].to_vec(),
+        trainer: None,
+        header,
+        title: None,
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        Rom::with_mode(raw, HeaderMode::Lenient)
+    }
+
+    // Loads `game.nes` patched with `game.ips`, `game.ups` or `game.bps`
+    // when one of them is next to it. Zip and gzip files are unpacked first
+    pub fn from_path(path: &Path) -> Result<Rom, LoadError> {
+        Rom::from_path_entry(path, None)
+    }
+
+    // Picks `entry` when `path` is a zip file with more than one ROM, see
+    // archive::rom_entries
+    pub fn from_path_entry(path: &Path, entry: Option<&str>) -> Result<Rom, LoadError> {
+        let mut raw = if archive::is_archive(path) {
+            archive::read(path, entry)?
+        } else {
+            fs::read(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?
+        };
+
+        let patch_path = PATCH_EXTENSIONS
+            .iter()
+            .map(|ext| path.with_extension(ext))
+            .find(|patch_path| patch_path.is_file());
+        if let Some(patch_path) = patch_path {
+            let patch = fs::read(&patch_path).map_err(|err| LoadError::Io(patch_path.clone(), err))?;
+            raw = patch::apply(&patch, &raw).map_err(|err| LoadError::Patch(patch_path, err))?;
+        }
+
+        Rom::from_bytes(&raw).map_err(|err| LoadError::Rom(path.to_path_buf(), err))
+    }
+
+    // iNES, NES 2.0 or UNIF, told apart by their tags
+    pub fn from_bytes(raw: &[u8]) -> Result<Rom, String> {
+        if !unif::is_unif(raw) {
+            return Rom::new(raw);
+        }
+        let mut rom = unif::parse(raw)?;
+        rom.apply_game_db(&BUILTIN_GAMES);
+        Ok(rom)
+    }
+
+    pub fn with_mode(raw: &[u8], mode: HeaderMode) -> Result<Rom, String> {
+        let header = RomHeader::parse(raw, mode)?;
+
+        let prg_rom_begin = HEADER_SIZE + if header.trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_begin = prg_rom_begin.saturating_add(header.prg_rom_size);
+        let chr_rom_end = chr_rom_begin.saturating_add(header.chr_rom_size);
+        if raw.len() < chr_rom_end {
+            return Err("Rom is shorter than its header says!".to_string());
+        }
+
+        let mut rom = Rom {
+            prg_rom: raw[prg_rom_begin..chr_rom_begin].to_vec(),
+            chr_rom: raw[chr_rom_begin..chr_rom_end].to_vec(),
+            trainer: header.trainer.then(|| raw[HEADER_SIZE..prg_rom_begin].to_vec()),
+            header,
+            title: None,
+        };
+        rom.apply_game_db(&BUILTIN_GAMES);
+        Ok(rom)
+    }
+
+    // Lets a database entry correct the header. Returns whether the ROM
+    // was found
+    pub fn apply_game_db(&mut self, db: &GameDb) -> bool {
+        match db.lookup(&self.prg_rom, &self.chr_rom) {
+            Some(game) => {
+                game.apply(&mut self.header);
+                self.title = Some(game.title.clone());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// These tests are older than the clippy gate and stay as they were written
+#[cfg(test)]
+#[allow(clippy::assertions_on_constants, clippy::identity_op, clippy::needless_borrows_for_generic_args)]
+pub mod test {
+    use std::io::Read;
+    use std::path::Path;
+    use std::fs::File;
+
+    use super::*;
+    use header::{CHR_ROM_PAGE_SIZE, PRG_ROM_PAGE_SIZE};
+
+    struct TestRom {
+        header: Vec<u8>,
+        trainer: Option<Vec<u8>>,
+        pgr_rom: Vec<u8>,
+        chr_rom: Vec<u8>
+    }
+
+    fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            rom.header.len()
+            + rom.trainer.as_ref().map_or(0, |t| t.len())
+            + rom.pgr_rom.len()
+            + rom.chr_rom.len()
+        );
+        result.extend(&rom.header);
+        if let Some(t) = rom.trainer {
+            result.extend(t)
+        }
+        result.extend(&rom.pgr_rom);
+        result.extend(&rom.chr_rom);
+        result
+    }
+
+    #[test]
+    fn wrong_nes_tag() {
+        let res = Rom::new(&vec![
+            0x01, 0x20, 0x00, 0x32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        match res {
+            Result::Ok(_) => assert!(false, "should not load room"),
+            Result::Err(s) => assert_eq!(s, "Wrong Nes Tag!"),
+        }
+    }
+
+    #[test]
+    fn correct_nes_tag() {
+        let res = Rom::new(&vec![
+            0x4E, 0x45, 0x53, 0x1A, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+        match res {
+            Result::Ok(_) => assert!(true),
+            Result::Err(s) => assert_eq!(s, "Wrong Nes Tag!"),
+        }
+    }
+
+    #[test]
+    fn test_pgp_chr() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x10, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            // 13 = 0b0000_0001_0000_0011
+            // 31 = 0b0000_0011_0000_0001
+            trainer: None,
+            pgr_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec![2; 1 * CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.prg_rom, vec![1; 2 * PRG_ROM_PAGE_SIZE]);
+        assert_eq!(rom.header.mapper, 0x13);
+        assert_eq!(rom.header.mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 
+                0x02, 0x01, 0x31 | 0b100, 0x10, 
+                00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            // 31 = 0b0000_0011_0000_0001
+            trainer: Some(vec![5; 512]),
+            pgr_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec![2; 1 * CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.prg_rom, vec![1; 2 * PRG_ROM_PAGE_SIZE]);
+        assert_eq!(rom.header.mapper, 0x13);
+        assert_eq!(rom.trainer, Some(vec![5; 512]));
+        assert_eq!(rom.header.mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn truncated_file() {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend(vec![0; PRG_ROM_PAGE_SIZE]);
+        assert_eq!(Rom::new(&raw).err().unwrap(), "Rom is shorter than its header says!");
+    }
+
+    #[test]
+    fn nes20_file() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A,
+                0x01, 0x00, 0x01, 0x08,
+                0x01, 0x00, 0x07, 0x07, 0x00, 0x00, 0x00, 0x01,
+            ],
+            trainer: None,
+            pgr_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.header.mapper, 0x100);
+        assert_eq!(rom.header.chr_ram_size, 8192);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.header.mirroring, Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn game_db_fixes_header() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+            trainer: None,
+            pgr_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        let mut rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.title, None);
+
+        let crc32 = crc32fast::hash(&test_rom[16..]);
+        let db = GameDb::parse(&format!("[Fixed]\ncrc32 = {:08X}\nmapper = 0\nmirroring = horizontal\n", crc32)).unwrap();
+        assert!(rom.apply_game_db(&db));
+
+        assert_eq!(rom.title.as_deref(), Some("Fixed"));
+        assert_eq!(rom.header.mapper, 0);
+        assert_eq!(rom.header.mirroring, Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn from_path_applies_patches() {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+        fs::copy("roms/snake.nes", &rom_path).unwrap();
+
+        let unpatched = Rom::from_path(&rom_path).unwrap();
+        assert_eq!(unpatched.prg_rom[0], fs::read("roms/snake.nes").unwrap()[16]);
+
+        // Two bytes at the start of PRG ROM
+        let mut ips = b"PATCH".to_vec();
+        ips.extend([0x00, 0x00, 0x10, 0x00, 0x02, 0xDE, 0xAD]);
+        ips.extend(b"EOF");
+        fs::write(dir.join("game.ips"), &ips).unwrap();
+
+        let patched = Rom::from_path(&rom_path).unwrap();
+        assert_eq!(patched.prg_rom[..2], [0xDE, 0xAD]);
+        assert_eq!(patched.prg_rom[2..], unpatched.prg_rom[2..]);
+
+        fs::write(dir.join("game.ips"), b"PATCH\x00").unwrap();
+        let err = Rom::from_path(&rom_path).err().unwrap();
+        assert!(matches!(err, LoadError::Patch(_, PatchError::Truncated(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn real_file(){
+        let path = Path::new("roms/snake.nes");
+        let mut file = File::open(&path).unwrap();
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer).unwrap();
+
+        let res = Rom::new(&buffer);
+
+        match res {
+            Result::Ok(_) => assert!(true),
+            Result::Err(_) => assert!(false)
+        }
+        assert_eq!(res.unwrap().title.as_deref(), Some("Snake"));
+
+
+    }
+}
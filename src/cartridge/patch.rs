@@ -0,0 +1,472 @@
+use derive_more::Display;
+
+// Soft patches, applied to the whole file (header included) before it's
+// parsed:
+//   IPS  byte ranges and RLE runs at 24 bit offsets, no checksums
+//   UPS  XOR against the source, with CRC32s of source, target and patch
+//   BPS  copy commands against source and target, with the same CRC32s
+
+pub const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// Source, target and patch CRC32s
+const FOOTER_SIZE: usize = 12;
+// UPS and BPS patches say how big their target is up front. Nothing NES
+// sized comes close, so anything bigger is rejected before allocating
+const MAX_TARGET_SIZE: usize = 64 << 20;
+
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum PatchError {
+    #[display("Not an IPS, UPS or BPS patch")]
+    UnknownFormat,
+
+    #[display("{_0} patch ends early")]
+    Truncated(PatchFormat),
+
+    #[display("{_0} patch copies from past the end of the ROM")]
+    OutOfBounds(PatchFormat),
+
+    #[display("Patch is for a {expected} byte ROM, this one is {actual} bytes")]
+    SourceSize { expected: usize, actual: usize },
+
+    #[display("Patch is for a ROM with CRC32 {expected:08X}, this one has {actual:08X}")]
+    SourceChecksum { expected: u32, actual: u32 },
+
+    #[display("Patched ROM has CRC32 {actual:08X}, the patch expects {expected:08X}")]
+    TargetChecksum { expected: u32, actual: u32 },
+
+    #[display("Patch has CRC32 {actual:08X} but says {expected:08X}, it's corrupt")]
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl std::error::Error for PatchError {}
+
+pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+    if patch.starts_with(IPS_MAGIC) {
+        Some(PatchFormat::Ips)
+    } else if patch.starts_with(UPS_MAGIC) {
+        Some(PatchFormat::Ups)
+    } else if patch.starts_with(BPS_MAGIC) {
+        Some(PatchFormat::Bps)
+    } else {
+        None
+    }
+}
+
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(patch, source),
+        Some(PatchFormat::Ups) => apply_ups(patch, source),
+        Some(PatchFormat::Bps) => apply_bps(patch, source),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: PatchFormat,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize, format: PatchFormat) -> Self {
+        Reader { data, pos, format }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or(PatchError::Truncated(self.format))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |value, &byte| value << 8 | byte as usize))
+    }
+
+    // UPS and BPS numbers: 7 bits per byte, last byte has bit 7 set, and
+    // every continuation adds one so each value has a single encoding
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            let add = ((byte & 0x7F) as usize).checked_mul(shift);
+            value = add.and_then(|add| value.checked_add(add)).ok_or(PatchError::Truncated(self.format))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::Truncated(self.format))?;
+            value = value.checked_add(shift).ok_or(PatchError::Truncated(self.format))?;
+        }
+    }
+}
+
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = Reader::new(patch, IPS_MAGIC.len(), PatchFormat::Ips);
+    let mut target = source.to_vec();
+
+    loop {
+        if reader.data[reader.pos..].starts_with(IPS_EOF) {
+            reader.pos += IPS_EOF.len();
+            // Some patches shrink the file by adding its new size
+            if let Ok(size) = reader.big_endian(3) {
+                target.truncate(size);
+            }
+            return Ok(target);
+        }
+
+        let offset = reader.big_endian(3)?;
+        let size = reader.big_endian(2)?;
+        let (data, size) = if size == 0 {
+            let run = reader.big_endian(2)?;
+            (None, run)
+        } else {
+            (Some(reader.bytes(size)?), size)
+        };
+
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => target[offset..offset + size].copy_from_slice(data),
+            None => target[offset..offset + size].fill(reader.byte()?),
+        }
+    }
+}
+
+// An IPS patch turning `source` into `target`, which has to be under 16MB
+pub fn create_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    // A record starting here would read as the end marker
+    const EOF_OFFSET: usize = 0x454F46;
+
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut offset = 0;
+    while offset < target.len() {
+        if source.get(offset) == Some(&target[offset]) {
+            offset += 1;
+            continue;
+        }
+        let start = if offset == EOF_OFFSET { offset - 1 } else { offset };
+        let mut end = offset;
+        while end < target.len() && end - start < 0xFFFF && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+        patch.extend(&(start as u32).to_be_bytes()[1..]);
+        patch.extend(((end - start) as u16).to_be_bytes());
+        patch.extend(&target[start..end]);
+        offset = end;
+    }
+    patch.extend(IPS_EOF);
+    if target.len() < source.len() {
+        patch.extend(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    patch
+}
+
+// Splits off the CRC32 footer after checking the source and the patch itself
+fn check_footer(patch: &[u8], source: &[u8], format: PatchFormat) -> Result<(usize, u32), PatchError> {
+    if patch.len() < UPS_MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::Truncated(format));
+    }
+    let body = patch.len() - FOOTER_SIZE;
+    let crc = |at: usize| u32::from_le_bytes(patch[at..at + 4].try_into().unwrap());
+
+    let actual = crc32fast::hash(&patch[..patch.len() - 4]);
+    if actual != crc(body + 8) {
+        return Err(PatchError::PatchChecksum { expected: crc(body + 8), actual });
+    }
+    let actual = crc32fast::hash(source);
+    if actual != crc(body) {
+        return Err(PatchError::SourceChecksum { expected: crc(body), actual });
+    }
+    Ok((body, crc(body + 4)))
+}
+
+fn check_target(target: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(target);
+    if actual != expected {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+    Ok(())
+}
+
+fn check_source_size(expected: usize, source: &[u8]) -> Result<(), PatchError> {
+    if expected != source.len() {
+        return Err(PatchError::SourceSize { expected, actual: source.len() });
+    }
+    Ok(())
+}
+
+fn check_target_size(target_size: usize, format: PatchFormat) -> Result<(), PatchError> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfBounds(format));
+    }
+    Ok(())
+}
+
+fn apply_ups(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (body, target_crc) = check_footer(patch, source, PatchFormat::Ups)?;
+    let mut reader = Reader::new(&patch[..body], UPS_MAGIC.len(), PatchFormat::Ups);
+
+    check_source_size(reader.number()?, source)?;
+    let target_size = reader.number()?;
+    check_target_size(target_size, PatchFormat::Ups)?;
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+
+    let out_of_bounds = PatchError::OutOfBounds(PatchFormat::Ups);
+    let mut pos: usize = 0;
+    while reader.pos < body {
+        pos = pos.checked_add(reader.number()?).ok_or(out_of_bounds)?;
+        // XOR bytes up to and including a zero, which can fall just past
+        // the end of the target
+        loop {
+            let xor = reader.byte()?;
+            if let Some(byte) = target.get_mut(pos) {
+                *byte = source.get(pos).copied().unwrap_or(0) ^ xor;
+            }
+            pos = pos.checked_add(1).ok_or(out_of_bounds)?;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+
+    let (body, target_crc) = check_footer(patch, source, PatchFormat::Bps)?;
+    let mut reader = Reader::new(&patch[..body], BPS_MAGIC.len(), PatchFormat::Bps);
+    let out_of_bounds = PatchError::OutOfBounds(PatchFormat::Bps);
+
+    check_source_size(reader.number()?, source)?;
+    let target_size = reader.number()?;
+    check_target_size(target_size, PatchFormat::Bps)?;
+    let metadata = reader.number()?;
+    reader.bytes(metadata)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.pos < body {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if target.len().checked_add(length).is_none_or(|end| end > target_size) {
+            return Err(out_of_bounds);
+        }
+
+        match action & 3 {
+            SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(source.get(start..start + length).ok_or(out_of_bounds)?);
+            }
+            TARGET_READ => target.extend_from_slice(reader.bytes(length)?),
+            command => {
+                // Offsets are relative to where the last copy of that kind ended
+                let delta = reader.number()?;
+                let offset = if command == SOURCE_COPY { &mut source_offset } else { &mut target_offset };
+                *offset = if delta & 1 != 0 {
+                    offset.checked_sub(delta >> 1)
+                } else {
+                    offset.checked_add(delta >> 1)
+                }
+                .ok_or(out_of_bounds)?;
+                let end = offset.checked_add(length).ok_or(out_of_bounds)?;
+
+                if command == SOURCE_COPY {
+                    target.extend_from_slice(source.get(*offset..end).ok_or(out_of_bounds)?);
+                } else {
+                    // The copy may overlap what it writes, so byte by byte
+                    for i in 0..length {
+                        let byte = *target.get(*offset + i).ok_or(out_of_bounds)?;
+                        target.push(byte);
+                    }
+                }
+                *offset = end;
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(PatchError::Truncated(PatchFormat::Bps));
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(mut value: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return out;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(crc32fast::hash(source).to_le_bytes());
+        patch.extend(crc32fast::hash(target).to_le_bytes());
+        patch.extend(crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 20] {
+            let encoded = number(value);
+            assert_eq!(Reader::new(&encoded, 0, PatchFormat::Bps).number(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn applies_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE run growing the file
+        patch.extend([0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend(b"EOF");
+
+        assert_eq!(apply(&patch, &[0; 4]).unwrap(), vec![0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC]);
+
+        patch.extend([0x00, 0x00, 0x02]);
+        assert_eq!(apply(&patch, &[0; 4]).unwrap(), vec![0, 0xAA]);
+        assert_eq!(apply(b"PATCH\x00\x00", &[0; 4]), Err(PatchError::Truncated(PatchFormat::Ips)));
+    }
+
+    #[test]
+    fn created_ips_patches_round_trip() {
+        let source = vec![0; 0x100];
+        let mut target = source.clone();
+        target[3..6].copy_from_slice(&[1, 2, 3]);
+        target[0xFF] = 9;
+        target.push(7);
+
+        let patch = create_ips(&source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        assert_eq!(create_ips(&source, &source), b"PATCHEOF");
+        assert_eq!(apply(&create_ips(&target, &source), &target).unwrap(), source);
+    }
+
+    #[test]
+    fn applies_ups() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 9, 3, 4, 7];
+
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(5));
+        patch.extend(number(1));
+        patch.extend([2 ^ 9, 0]);
+        patch.extend(number(1));
+        patch.extend([7, 0]);
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        assert!(matches!(apply(&patch, &[1, 2, 3, 5]), Err(PatchError::SourceChecksum { .. })));
+        assert!(matches!(apply(&patch, &[1, 2, 3]), Err(PatchError::SourceChecksum { .. })));
+    }
+
+    #[test]
+    fn applies_bps() {
+        let source = b"ABCDEFGH";
+        let target = b"ABCDxyzxyzEFGH";
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(4));
+        patch.extend(b"meta");
+        // SourceRead 4, TargetRead "xyz", TargetCopy 3 from 4, SourceCopy 4 from 4
+        patch.extend(number(3 << 2));
+        patch.extend(number((2 << 2) | 1));
+        patch.extend(b"xyz");
+        patch.extend(number((2 << 2) | 3));
+        patch.extend(number(4 << 1));
+        patch.extend(number((3 << 2) | 2));
+        patch.extend(number(4 << 1));
+        let patch = with_footer(patch, source, target);
+
+        assert_eq!(apply(&patch, source).unwrap(), target);
+    }
+
+    #[test]
+    fn reports_corrupt_patches_and_wrong_targets() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(0));
+        patch.extend([1, 0]);
+
+        let good = with_footer(patch.clone(), &source, &[0, 2, 3, 4]);
+        let mut corrupt = good.clone();
+        corrupt[6] ^= 1;
+        assert!(matches!(apply(&corrupt, &source), Err(PatchError::PatchChecksum { .. })));
+
+        let wrong_target = with_footer(patch, &source, &[9, 9, 9, 9]);
+        assert!(matches!(apply(&wrong_target, &source), Err(PatchError::TargetChecksum { .. })));
+
+        assert_eq!(apply(b"nope", &source), Err(PatchError::UnknownFormat));
+    }
+
+    #[test]
+    fn offsets_past_the_address_space_are_out_of_bounds() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(number(4));
+        patch.extend(number(4));
+        patch.extend(number(1));
+        patch.extend([1, 0]);
+        patch.extend(number(usize::MAX - 1));
+        patch.extend([1, 0]);
+        let patch = with_footer(patch, &source, &source);
+
+        assert_eq!(apply(&patch, &source), Err(PatchError::OutOfBounds(PatchFormat::Ups)));
+    }
+
+    #[test]
+    fn huge_targets_are_out_of_bounds() {
+        let source = [1, 2, 3, 4];
+        for (magic, format) in [(UPS_MAGIC, PatchFormat::Ups), (BPS_MAGIC, PatchFormat::Bps)] {
+            let mut patch = magic.to_vec();
+            patch.extend(number(4));
+            patch.extend(number(usize::MAX >> 1));
+            patch.extend(number(0));
+            let patch = with_footer(patch, &source, &source);
+
+            assert_eq!(apply(&patch, &source), Err(PatchError::OutOfBounds(format)));
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use super::Mirroring;
+use super::Rom;
+use super::header::{HeaderFormat, RomHeader, Timing};
+
+// UNIF files are a 32 byte header ("UNIF", revision, padding) and then
+// chunks of a 4 byte ID, a little endian length and the data. The board is
+// named in MAPR instead of numbered, ROM comes in PRG0-PRGF and CHR0-CHRF.
+const UNIF_TAG: &[u8] = b"UNIF";
+const HEADER_SIZE: usize = 32;
+const CHR_RAM_SIZE: usize = 8192;
+const PRG_RAM_SIZE: usize = 8192;
+
+// Board names as in MAPR, without the NES-/UNL-/HVC-/BTL-/BMC- prefix
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("RROM", 0),
+    ("RROM-128", 0),
+    ("SAROM", 1),
+    ("SBROM", 1),
+    ("SCROM", 1),
+    ("SEROM", 1),
+    ("SGROM", 1),
+    ("SKROM", 1),
+    ("SLROM", 1),
+    ("SL1ROM", 1),
+    ("SNROM", 1),
+    ("SOROM", 1),
+    ("SUROM", 1),
+    ("SXROM", 1),
+    ("UNROM", 2),
+    ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4),
+    ("TEROM", 4),
+    ("TFROM", 4),
+    ("TGROM", 4),
+    ("TKROM", 4),
+    ("TLROM", 4),
+    ("TNROM", 4),
+    ("TR1ROM", 4),
+    ("TSROM", 4),
+    ("TVROM", 4),
+    ("EKROM", 5),
+    ("ELROM", 5),
+    ("ETROM", 5),
+    ("EWROM", 5),
+    ("AMROM", 7),
+    ("ANROM", 7),
+    ("AN1ROM", 7),
+    ("AOROM", 7),
+    ("PNROM", 9),
+    ("PEEOROM", 9),
+    ("FJROM", 10),
+    ("FKROM", 10),
+    ("CPROM", 13),
+    ("BNROM", 34),
+    ("NINA-001", 34),
+    ("Supervision16in1", 53),
+    ("MARIO1-MALEE2", 55),
+    ("GNROM", 66),
+    ("MHROM", 66),
+    ("NTBROM", 68),
+    ("TLSROM", 118),
+    ("TKSROM", 118),
+    ("TQROM", 119),
+    ("H2288", 123),
+    ("Sachen-8259D", 137),
+    ("Sachen-8259B", 138),
+    ("Sachen-8259C", 139),
+    ("Sachen-8259A", 141),
+    ("Sachen-74LS374N", 150),
+    ("Super24in1SC03", 176),
+    ("8237", 215),
+];
+
+const BOARD_PREFIXES: [&str; 5] = ["NES-", "UNL-", "HVC-", "BTL-", "BMC-"];
+
+pub fn is_unif(raw: &[u8]) -> bool {
+    raw.starts_with(UNIF_TAG)
+}
+
+pub fn board_mapper(board: &str) -> Option<u16> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+    BOARDS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper)| mapper)
+}
+
+// Chunk strings are zero terminated, but not always
+fn chunk_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+pub fn parse(raw: &[u8]) -> Result<Rom, String> {
+    if !is_unif(raw) {
+        return Err("Wrong UNIF Tag!".to_string());
+    }
+    if raw.len() < HEADER_SIZE {
+        return Err("UNIF header is cut short!".to_string());
+    }
+
+    let mut board = None;
+    let mut name = None;
+    let mut prg: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = None;
+    let mut battery = false;
+    let mut chr_ram = false;
+    let mut timing = Timing::Ntsc;
+
+    let mut pos = HEADER_SIZE;
+    while pos < raw.len() {
+        let header = raw.get(pos..pos + 8).ok_or("UNIF chunk header is cut short!")?;
+        let id = &header[..4];
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        let data = raw
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| format!("UNIF chunk {} is cut short!", String::from_utf8_lossy(id)))?;
+        pos += 8 + len;
+
+        // PRG0-PRGF and CHR0-CHRF, numbered in hex
+        let bank = (id[3] as char).to_digit(16).map(|bank| bank as usize);
+        match (&id[..3], bank) {
+            (b"PRG", Some(bank)) => prg[bank] = Some(data),
+            (b"CHR", Some(bank)) => chr[bank] = Some(data),
+            _ => match id {
+                b"MAPR" => board = Some(chunk_string(data)),
+                b"NAME" => name = Some(chunk_string(data)),
+                b"BATR" => battery = true,
+                b"VROR" => chr_ram = true,
+                b"MIRR" => {
+                    mirroring = match data.first() {
+                        Some(0) => Some(Mirroring::HORIZONTAL),
+                        Some(1) => Some(Mirroring::VERTICAL),
+                        Some(4) => Some(Mirroring::FOUR_SCREEN),
+                        // Single screen and mapper controlled, left to the mapper
+                        _ => None,
+                    }
+                }
+                b"TVCI" => {
+                    timing = match data.first() {
+                        Some(1) => Timing::Pal,
+                        Some(2) => Timing::Multi,
+                        _ => Timing::Ntsc,
+                    }
+                }
+                // CRCs, dumper info, controllers and comments
+                _ => {}
+            },
+        }
+    }
+
+    let board = board.ok_or("UNIF file has no MAPR chunk!")?;
+    let mapper = board_mapper(&board).ok_or_else(|| format!("UNIF board {} has no mapper number", board))?;
+
+    let prg_rom: Vec<u8> = prg.iter().flatten().flat_map(|bank| bank.iter().copied()).collect();
+    let chr_rom: Vec<u8> = chr.iter().flatten().flat_map(|bank| bank.iter().copied()).collect();
+    if prg_rom.is_empty() {
+        return Err("UNIF file has no PRG chunks!".to_string());
+    }
+
+    let mut header = RomHeader::new(mapper, mirroring.unwrap_or(Mirroring::HORIZONTAL));
+    header.format = HeaderFormat::Unif;
+    header.prg_rom_size = prg_rom.len();
+    header.timing = timing;
+    header.battery = battery;
+    if battery {
+        header.prg_ram_size = 0;
+        header.prg_nvram_size = PRG_RAM_SIZE;
+    }
+    // VROR marks CHR chunks that are really RAM
+    let chr_rom = if chr_ram { Vec::new() } else { chr_rom };
+    header.chr_rom_size = chr_rom.len();
+    if chr_rom.is_empty() {
+        header.chr_ram_size = CHR_RAM_SIZE;
+    }
+
+    Ok(Rom {
+        prg_rom,
+        chr_rom,
+        trainer: None,
+        header,
+        title: name.filter(|name| !name.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut raw = b"UNIF".to_vec();
+        raw.extend(7u32.to_le_bytes());
+        raw.resize(HEADER_SIZE, 0);
+        for chunk in chunks {
+            raw.extend(chunk);
+        }
+        raw
+    }
+
+    #[test]
+    fn maps_board_names() {
+        assert_eq!(board_mapper("NES-NROM-256"), Some(0));
+        assert_eq!(board_mapper("NES-TLROM"), Some(4));
+        assert_eq!(board_mapper("UNL-Sachen-8259A"), Some(141));
+        assert_eq!(board_mapper("BMC-Unknown"), None);
+    }
+
+    #[test]
+    fn parses_chunks() {
+        let raw = unif(&[
+            chunk(b"MAPR", b"NES-SNROM\0"),
+            chunk(b"NAME", b"Some Game\0"),
+            chunk(b"PRG1", &[2; 0x4000]),
+            chunk(b"PRG0", &[1; 0x4000]),
+            chunk(b"CHR0", &[3; 0x2000]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[0]),
+            chunk(b"TVCI", &[1]),
+            chunk(b"DINF", &[0; 204]),
+        ]);
+        let rom = parse(&raw).unwrap();
+
+        assert_eq!(rom.header.format, HeaderFormat::Unif);
+        assert_eq!(rom.header.mapper, 1);
+        assert_eq!(rom.title.as_deref(), Some("Some Game"));
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.prg_rom[0x4000], 2);
+        assert_eq!(rom.chr_rom, vec![3; 0x2000]);
+        assert_eq!(rom.header.mirroring, Mirroring::VERTICAL);
+        assert!(rom.header.battery);
+        assert_eq!(rom.header.prg_nvram_size, PRG_RAM_SIZE);
+        assert_eq!(rom.header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn chr_ram_boards() {
+        let raw = unif(&[chunk(b"MAPR", b"UNROM"), chunk(b"PRG0", &[0; 0x8000])]);
+        let rom = parse(&raw).unwrap();
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.header.chr_ram_size, CHR_RAM_SIZE);
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert_eq!(
+            parse(&unif(&[chunk(b"PRG0", &[0; 16])])).err().unwrap(),
+            "UNIF file has no MAPR chunk!"
+        );
+        assert_eq!(
+            parse(&unif(&[chunk(b"MAPR", b"BMC-Mystery\0"), chunk(b"PRG0", &[0; 16])])).err().unwrap(),
+            "UNIF board BMC-Mystery has no mapper number"
+        );
+        let mut truncated = unif(&[chunk(b"MAPR", b"NROM\0")]);
+        truncated.extend(b"PRG0\x00\x40\x00\x00");
+        assert_eq!(parse(&truncated).err().unwrap(), "UNIF chunk PRG0 is cut short!");
+    }
+}
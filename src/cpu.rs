@@ -1,8 +1,9 @@
 use crate::bus::BusOP;
-use crate::byte_utils;
+use crate::error::EmuError;
+use crate::logging::verbose;
 use crate::opcodes;
-
-use std::collections::HashMap;
+use crate::opcodes::Instruction;
+use crate::status_flags::StatusFlags;
 
 #[derive(Default, Debug)]
 #[allow(non_camel_case_types)]
@@ -23,14 +24,20 @@ pub enum AddressingMode {
 }
 
 const STACK_RESET: u8 = 0xFD;
+// What ANE and LXA OR into A before the AND. It varies between chips and
+// with temperature, 0xEE is what most test suites expect
+pub const UNSTABLE_MAGIC: u8 = 0xEE;
 
 pub struct CPU<T: BusOP> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
-    pub status: u8,
+    pub status: StatusFlags,
     pub stack_pointer: u8,
     pub program_counter: u16,
+    // Set by the KIL/JAM opcodes, only a reset gets the CPU going again
+    pub halted: bool,
+    pub unstable_magic: u8,
     // memory: [u8; 0xFFFF],
     pub bus: T,
 }
@@ -42,7 +49,7 @@ pub trait Mem {
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
         let hi = self.mem_read(pos.wrapping_add(1)) as u16;
-        (hi << 8) | (lo as u16)
+        (hi << 8) | lo
     }
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
@@ -57,22 +64,19 @@ fn page_cross(lhs: u16, rhs: u16) -> bool {
     (lhs & 0xFF00) != (rhs & 0xFF00)
 }
 
+// Every CPU access takes one cycle. The bus is ticked first so the PPU has
+// caught up by the time the access lands. Use `cpu.bus` directly to look
+// at memory without spending cycles
 impl<T: BusOP> Mem for CPU<T> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.tick(1);
         self.bus.mem_read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.bus.tick(1);
         self.bus.mem_write(addr, data)
     }
-
-    fn mem_read_u16(&mut self, pos: u16) -> u16 {
-        self.bus.mem_read_u16(pos)
-    }
-
-    fn mem_write_u16(&mut self, pos: u16, data: u16) {
-        self.bus.mem_write_u16(pos, data);
-    }
 }
 
 impl<T: BusOP> CPU<T> {
@@ -81,11 +85,13 @@ impl<T: BusOP> CPU<T> {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: 0b0001_00100,
+            status: StatusFlags::new(),
             stack_pointer: STACK_RESET,
             program_counter: 0,
+            halted: false,
+            unstable_magic: UNSTABLE_MAGIC,
             // memory: [0; 0xFFFF],
-            bus: bus,
+            bus,
         }
     }
 
@@ -95,7 +101,7 @@ impl<T: BusOP> CPU<T> {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: 0,
+            status: StatusFlags::new(),
             stack_pointer: STACK_RESET,
             program_counter: 0x8000,
             // memory: [0; 0xFFFF],
@@ -120,12 +126,14 @@ impl<T: BusOP> CPU<T> {
 
             AddressingMode::ZeroPage_X => {
                 let pos = self.mem_read(self.program_counter);
+                self.mem_read(pos as u16);
                 let addr = pos.wrapping_add(self.register_x) as u16;
                 (addr, false)
             }
 
             AddressingMode::ZeroPage_Y => {
                 let pos = self.mem_read(self.program_counter);
+                self.mem_read(pos as u16);
                 let addr = pos.wrapping_add(self.register_y) as u16;
                 (addr, false)
             }
@@ -157,8 +165,9 @@ impl<T: BusOP> CPU<T> {
 
             AddressingMode::Indirect_X => {
                 let base = self.mem_read(self.program_counter);
+                self.mem_read(base as u16);
 
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
+                let ptr: u8 = base.wrapping_add(self.register_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
                 ((hi as u16) << 8 | (lo as u16), false)
@@ -168,7 +177,7 @@ impl<T: BusOP> CPU<T> {
                 let base = self.mem_read(self.program_counter);
 
                 let lo = self.mem_read(base as u16);
-                let hi = self.mem_read((base as u8).wrapping_add(1) as u16);
+                let hi = self.mem_read(base.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 let deref = deref_base.wrapping_add(self.register_y as u16);
                 (deref, page_cross(deref_base, deref))
@@ -182,15 +191,40 @@ impl<T: BusOP> CPU<T> {
         }
     }
 
+    // Indexing that carries into the high byte first reads from the address
+    // before the carry is fixed up, so reads take an extra cycle then
+    fn read_address(&mut self, mode: &AddressingMode) -> u16 {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        if page_cross {
+            self.mem_read(addr.wrapping_sub(0x100));
+        }
+        addr
+    }
+
+    // Stores and read-modify-writes always spend the dummy read
+    fn write_address(&mut self, mode: &AddressingMode) -> u16 {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        match mode {
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y | AddressingMode::Indirect_Y => {
+                let unfixed = if page_cross { addr.wrapping_sub(0x100) } else { addr };
+                self.mem_read(unfixed);
+            }
+            _ => {}
+        }
+        addr
+    }
+
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        self.status = 0b0001_00100;
+        self.status = StatusFlags::new();
         self.stack_pointer = STACK_RESET;
+        self.halted = false;
 
+        // Five cycles of dummy reads before the vector is fetched
+        self.bus.tick(5);
         self.program_counter = self.mem_read_u16(0xFFFC);
-        self.bus.tick(7);
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -209,12 +243,16 @@ impl<T: BusOP> CPU<T> {
         self.push_stack(lo);
     }
 
+    // Pulls spend a cycle reading the stack before SP is incremented
+    fn stack_dummy_read(&mut self) {
+        self.mem_read(0x0100 | self.stack_pointer as u16);
+    }
+
     pub fn pop_stack(&mut self) -> u8 {
-        self.stack_pointer += 1;
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
         let hi = 0x01;
         let addr = (hi << 8) | self.stack_pointer as u16;
-        let data = self.mem_read(addr);
-        data
+        self.mem_read(addr)
     }
 
     pub fn push_stack(&mut self, data: u8) {
@@ -235,47 +273,39 @@ impl<T: BusOP> CPU<T> {
         (hi << 8) | lo
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<(), EmuError> {
         self.load(program);
         self.reset();
-        self.run();
+        self.run()
     }
 
-    pub fn load_and_run_no_reset(&mut self, program: Vec<u8>) {
+    pub fn load_and_run_no_reset(&mut self, program: Vec<u8>) -> Result<(), EmuError> {
         self.load(program);
         self.program_counter = self.mem_read_u16(0xFFFC);
-        self.run();
+        self.run()
     }
 
     fn lda(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let value = self.mem_read(addr);
 
         self.register_a = value;
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn lax(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let value = self.mem_read(addr);
 
         self.register_a = value;
         self.register_x = self.register_a;
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn las(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let value = self.mem_read(addr) & self.stack_pointer;
 
         self.register_a = value;
@@ -283,73 +313,49 @@ impl<T: BusOP> CPU<T> {
         self.stack_pointer = value;
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn ldx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let value = self.mem_read(addr);
 
         self.register_x = value;
         self.update_zero_flag(self.register_x);
         self.update_negative_flag(self.register_x);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn ldy(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let value = self.mem_read(addr);
 
         self.register_y = value;
         self.update_zero_flag(self.register_y);
         self.update_negative_flag(self.register_y);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
 
         self.mem_write(addr, self.register_a);
     }
 
     fn stx(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
 
         self.mem_write(addr, self.register_x);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn sax(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
         let data = self.register_a & self.register_x;
 
         self.mem_write(addr, data);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn sty(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
 
         self.mem_write(addr, self.register_y);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn tax(&mut self) {
@@ -405,43 +411,48 @@ impl<T: BusOP> CPU<T> {
     fn asl_accumulator(&mut self) {
         self.update_carry_msb(self.register_a);
 
-        self.register_a = self.register_a << 1;
+        self.register_a <<= 1;
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
     }
 
-    fn asl(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+    // Read-modify-writes write the unmodified value back before the result
+    fn asl(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.write_address(mode);
         let mut data = self.mem_read(addr);
+        self.mem_write(addr, data);
         self.update_carry_msb(data);
 
-        data = data << 1;
+        data <<= 1;
         self.update_zero_flag(data);
         self.update_negative_flag(data);
         self.mem_write(addr, data);
+        data
     }
 
     fn lsr_accumulator(&mut self) {
         self.update_carry_lsb(self.register_a);
 
-        self.register_a = self.register_a >> 1;
+        self.register_a >>= 1;
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
     }
 
-    fn lsr(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+    fn lsr(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.write_address(mode);
         let mut data = self.mem_read(addr);
+        self.mem_write(addr, data);
         self.update_carry_lsb(data);
 
-        data = data >> 1;
+        data >>= 1;
         self.update_zero_flag(data);
         self.update_negative_flag(data);
         self.mem_write(addr, data);
+        data
     }
 
     fn rol_accumulator(&mut self) {
-        let carry = byte_utils::get_carry(self.status);
+        let carry = self.status.carry();
         self.update_carry_msb(self.register_a);
 
         self.register_a = (self.register_a << 1) | carry;
@@ -449,21 +460,23 @@ impl<T: BusOP> CPU<T> {
         self.update_negative_flag(self.register_a);
     }
 
-    fn rol(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+    fn rol(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.write_address(mode);
         let mut data = self.mem_read(addr);
+        self.mem_write(addr, data);
 
-        let carry = byte_utils::get_carry(self.status);
+        let carry = self.status.carry();
         self.update_carry_msb(data);
 
         data = (data << 1) | carry;
         self.update_zero_flag(data);
         self.update_negative_flag(data);
         self.mem_write(addr, data);
+        data
     }
 
     fn ror_accumulator(&mut self) {
-        let carry = byte_utils::get_carry(self.status);
+        let carry = self.status.carry();
         self.update_carry_lsb(self.register_a);
 
         self.register_a = (self.register_a >> 1) | (carry << 7);
@@ -471,25 +484,23 @@ impl<T: BusOP> CPU<T> {
         self.update_negative_flag(self.register_a);
     }
 
-    fn ror(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+    fn ror(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.write_address(mode);
         let mut data = self.mem_read(addr);
+        self.mem_write(addr, data);
 
-        let carry = byte_utils::get_carry(self.status);
+        let carry = self.status.carry();
         self.update_carry_lsb(data);
 
         data = (data >> 1) | (carry << 7);
         self.update_zero_flag(data);
         self.update_negative_flag(data);
         self.mem_write(addr, data);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
+        data
     }
 
     fn and(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let data = self.mem_read(addr);
         self.register_a &= data;
 
@@ -498,92 +509,83 @@ impl<T: BusOP> CPU<T> {
     }
 
     fn or(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let data = self.mem_read(addr);
         self.register_a |= data;
 
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn eor(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let data = self.mem_read(addr);
         self.register_a ^= data;
 
         self.update_zero_flag(self.register_a);
         self.update_negative_flag(self.register_a);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn bit(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let data = self.mem_read(addr);
         self.update_zero_flag(self.register_a & data);
 
-        let mask = data & 0b1100_0000;
-        self.status &= 0b0011_1111;
-        self.status |= mask;
-
-        if page_cross {
-            self.bus.tick(1);
-        }
+        self.status.set(StatusFlags::OVERFLOW, data & 0b0100_0000 != 0);
+        self.status.set(StatusFlags::NEGATIVE, data & 0b1000_0000 != 0);
     }
 
+    // The combined read-modify-write ops take the fixed RMW timing, so they
+    // must not go through `or`/`adc_sbc` and pick up a page cross penalty
     fn slo(&mut self, mode: &AddressingMode) {
-        self.asl(mode);
-        self.or(mode);
+        let data = self.asl(mode);
+        self.register_a |= data;
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
     }
 
     fn rla(&mut self, mode: &AddressingMode) {
-        self.rol(mode);
-        self.and(mode);
+        let data = self.rol(mode);
+        self.register_a &= data;
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
     }
 
     fn sre(&mut self, mode: &AddressingMode) {
-        self.lsr(mode);
-        self.eor(mode);
+        let data = self.lsr(mode);
+        self.register_a ^= data;
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
     }
 
     fn rra(&mut self, mode: &AddressingMode) {
-        self.ror(mode);
-        self.adc_sbc(mode, false);
+        let data = self.ror(mode);
+        self.add_to_register_a(data);
     }
 
     fn compare(&mut self, mode: &AddressingMode, compare_with: u8) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let data = self.mem_read(addr);
         let result = compare_with.wrapping_sub(data);
 
-        if data <= compare_with {
-            byte_utils::set_carry(&mut self.status);
-        } else {
-            byte_utils::unset_carry(&mut self.status);
-        }
+        self.update_carry(data <= compare_with);
 
         self.update_zero_flag(result);
         self.update_negative_flag(result);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn adc_sbc(&mut self, mode: &AddressingMode, sub: bool) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.read_address(mode);
         let mut data = self.mem_read(addr);
         if sub {
             data = !data;
         }
 
-        let carry = byte_utils::get_carry(self.status);
+        self.add_to_register_a(data);
+    }
+
+    fn add_to_register_a(&mut self, data: u8) {
+        let carry = self.status.carry();
 
         let (result1, carry1) = self.register_a.overflowing_add(data);
         let (result, carry) = result1.overflowing_add(carry);
@@ -594,10 +596,6 @@ impl<T: BusOP> CPU<T> {
         self.update_negative_flag(result);
         self.update_overflow(overflow);
         self.update_carry(carry1 || carry);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     fn branch(&mut self, cmp: bool) {
@@ -609,37 +607,43 @@ impl<T: BusOP> CPU<T> {
                 .program_counter
                 .wrapping_add(1)
                 .wrapping_add_signed(offset as i8 as i16);
-            self.bus.tick(1);
+            // The CPU reads the next opcode while adding the offset, and
+            // again at the unfixed address when the branch crosses a page
+            self.mem_read(prev_pc.wrapping_add(1));
             if page_cross(prev_pc.wrapping_add(1), self.program_counter) {
-                self.bus.tick(1);
+                let unfixed = (prev_pc.wrapping_add(1) & 0xFF00) | (self.program_counter & 0x00FF);
+                self.mem_read(unfixed);
             }
         }
     }
 
     fn jmp(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let (addr, _page_cross) = self.get_operand_address(mode);
 
         self.program_counter = addr;
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
-    fn jsr(&mut self, mode: &AddressingMode) {
-        let (new_pc, _page_cross) = self.get_operand_address(mode);
-        self.push_stack_u16(self.program_counter + 1);
-        self.program_counter = new_pc;
+    // JSR fetches the high byte of the target only after pushing the return
+    // address, so it can't go through get_operand_address
+    fn jsr(&mut self) {
+        let lo = self.mem_read(self.program_counter) as u16;
+        self.stack_dummy_read();
+        self.push_stack_u16(self.program_counter.wrapping_add(1));
+        let hi = self.mem_read(self.program_counter.wrapping_add(1)) as u16;
+        self.program_counter = (hi << 8) | lo;
     }
 
     fn rts(&mut self) {
+        self.stack_dummy_read();
         let new_pc = self.pop_stack_u16();
-        self.program_counter = new_pc + 1;
+        self.mem_read(new_pc);
+        self.program_counter = new_pc.wrapping_add(1);
     }
 
     fn rti(&mut self) {
-        self.status &= 0b0011_0000;
-        self.status |= self.pop_stack() & 0b1100_1111;
+        self.stack_dummy_read();
+        let data = self.pop_stack();
+        self.status.from_stack(data);
         self.program_counter = self.pop_stack_u16();
     }
 
@@ -648,6 +652,7 @@ impl<T: BusOP> CPU<T> {
     }
 
     fn pla(&mut self) {
+        self.stack_dummy_read();
         self.register_a = self.pop_stack();
 
         self.update_zero_flag(self.register_a);
@@ -664,19 +669,22 @@ impl<T: BusOP> CPU<T> {
         self.update_zero_flag(self.register_x);
     }
 
-    fn inc(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
+    fn inc(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.write_address(mode);
         let value = self.mem_read(addr);
+        self.mem_write(addr, value);
         let x = value.wrapping_add(1);
         self.mem_write(addr, x);
 
         self.update_negative_flag(x);
         self.update_zero_flag(x);
+        x
     }
 
     fn dec(&mut self, mode: &AddressingMode) {
-        let (addr, _) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
         let value = self.mem_read(addr);
+        self.mem_write(addr, value);
         let x = value.wrapping_sub(1);
         self.mem_write(addr, x);
 
@@ -685,469 +693,685 @@ impl<T: BusOP> CPU<T> {
     }
 
     fn isb(&mut self, mode: &AddressingMode) {
-        self.inc(&mode);
-        self.adc_sbc(&mode, true);
+        let data = self.inc(mode);
+        self.add_to_register_a(!data);
     }
 
-    fn ahx(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
-        let value = self.register_a & self.register_x & ((addr >> 8) as u8);
+    // SHA/SHX/SHY/TAS store `value & (H + 1)`, H being the high byte of the
+    // base address. When indexing crosses a page the stored value also
+    // replaces the high byte of the target address
+    fn unstable_store(&mut self, mode: &AddressingMode, value: u8) {
+        let (addr, page_cross) = self.get_operand_address(mode);
+        let hi = (addr >> 8) as u8;
+        let base_hi = if page_cross { hi.wrapping_sub(1) } else { hi };
+        self.mem_read(((base_hi as u16) << 8) | (addr & 0x00FF));
+        let value = value & base_hi.wrapping_add(1);
+
+        let addr = if page_cross {
+            ((value as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
         self.mem_write(addr, value);
     }
 
+    fn ahx(&mut self, mode: &AddressingMode) {
+        self.unstable_store(mode, self.register_a & self.register_x);
+    }
+
     fn shy(&mut self, mode: &AddressingMode) {
-        let base = self.mem_read_u16(self.program_counter);
-        // let addr = base.wrapping_add(self.register_x as u16);
+        self.unstable_store(mode, self.register_y);
+    }
 
-        // let (addr, _page_cross) = self.get_operand_address(mode);
-        let saddr = (base >> 8) as u8;
-        let value = self.register_y & (saddr + 1);
-        let mut addr: u16 = ((base & 0x00ff) + self.register_x as u16) & 0xff;
-        addr |= (value as u16) << 8;
+    fn shx(&mut self, mode: &AddressingMode) {
+        self.unstable_store(mode, self.register_x);
+    }
 
-        println!("SHY ({:02X} & {:02X} = {:02X}) writing to {:04X} = {}", saddr, self.register_y, value, addr, value);
-        self.mem_write(addr, value);
+    fn tas(&mut self, mode: &AddressingMode) {
+        self.stack_pointer = self.register_a & self.register_x;
+        self.unstable_store(mode, self.stack_pointer);
+    }
 
+    fn anc(&mut self, mode: &AddressingMode) {
+        self.and(mode);
+        self.update_carry_msb(self.register_a);
     }
 
-    fn shx(&mut self, mode: &AddressingMode) {
-        let (addr, _page_cross) = self.get_operand_address(mode);
-        let value = self.register_x & ((addr >> 8) as u8);
-        self.mem_write(addr, value);
+    fn alr(&mut self, mode: &AddressingMode) {
+        self.and(mode);
+        self.lsr_accumulator();
+    }
+
+    fn arr(&mut self, mode: &AddressingMode) {
+        let addr = self.read_address(mode);
+        let data = self.mem_read(addr) & self.register_a;
+        let carry = self.status.carry();
+
+        self.register_a = (data >> 1) | (carry << 7);
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
+        self.update_carry(self.register_a & 0b0100_0000 != 0);
+        self.update_overflow(((self.register_a >> 6) ^ (self.register_a >> 5)) & 1 != 0);
+    }
+
+    fn axs(&mut self, mode: &AddressingMode) {
+        let addr = self.read_address(mode);
+        let data = self.mem_read(addr);
+        let and = self.register_a & self.register_x;
+
+        self.register_x = and.wrapping_sub(data);
+        self.update_carry(and >= data);
+        self.update_zero_flag(self.register_x);
+        self.update_negative_flag(self.register_x);
+    }
+
+    fn ane(&mut self, mode: &AddressingMode) {
+        let addr = self.read_address(mode);
+        let data = self.mem_read(addr);
+
+        self.register_a = (self.register_a | self.unstable_magic) & self.register_x & data;
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
+    }
+
+    fn lxa(&mut self, mode: &AddressingMode) {
+        let addr = self.read_address(mode);
+        let data = self.mem_read(addr);
+
+        self.register_a = (self.register_a | self.unstable_magic) & data;
+        self.register_x = self.register_a;
+        self.update_zero_flag(self.register_a);
+        self.update_negative_flag(self.register_a);
     }
 
     fn dcp(&mut self, mode: &AddressingMode) {
-        let (addr, page_cross) = self.get_operand_address(mode);
+        let addr = self.write_address(mode);
         let value = self.mem_read(addr);
+        self.mem_write(addr, value);
         let (data, _carry) = value.overflowing_sub(1);
         self.mem_write(addr, data);
 
         let result = self.register_a.wrapping_sub(data);
 
-        if self.register_a >= data {
-            byte_utils::set_carry(&mut self.status);
-        } else {
-            byte_utils::unset_carry(&mut self.status);
-        }
-
-        // self.update_carry(carry);
+        self.update_carry(self.register_a >= data);
         self.update_negative_flag(result);
         self.update_zero_flag(result);
-
-        if page_cross {
-            self.bus.tick(1);
-        }
     }
 
     /* TODO: Implement delayed effect of updating the I flag */
     fn plp(&mut self) {
-        self.status &= 0b0011_0000;
-        self.status |= self.pop_stack() & 0b1100_1111;
+        self.stack_dummy_read();
+        let data = self.pop_stack();
+        self.status.from_stack(data);
     }
 
     fn php(&mut self) {
-        self.push_stack(self.status | 0b0011_0000);
+        self.push_stack(self.status.to_stack(true));
     }
 
     fn brk(&mut self) {
-        self.push_stack_u16(self.program_counter + 2);
-        self.push_stack(self.status | 0b0011_0000);
-        byte_utils::set_interrupt_disable(&mut self.status);
+        // PC is past the opcode, BRK returns past its padding byte
+        self.push_stack_u16(self.program_counter.wrapping_add(1));
+        self.push_stack(self.status.to_stack(true));
+        self.status.insert(StatusFlags::INTERRUPT_DISABLE);
         self.program_counter = self.mem_read_u16(0xFFFE);
     }
 
     fn update_carry(&mut self, cond: bool) {
-        if cond {
-            byte_utils::set_carry(&mut self.status);
-        } else {
-            byte_utils::unset_carry(&mut self.status);
-        }
+        self.status.set(StatusFlags::CARRY, cond);
     }
     fn update_overflow(&mut self, cond: bool) {
-        if cond {
-            byte_utils::set_overflow(&mut self.status);
-        } else {
-            byte_utils::unset_overflow(&mut self.status);
-        }
+        self.status.set(StatusFlags::OVERFLOW, cond);
     }
 
     fn update_carry_lsb(&mut self, data: u8) {
-        if data & 0b0000_0001 != 0 {
-            byte_utils::set_carry(&mut self.status);
-        } else {
-            byte_utils::unset_carry(&mut self.status);
-        }
+        self.status.set(StatusFlags::CARRY, data & 0b0000_0001 != 0);
     }
 
     fn update_carry_msb(&mut self, data: u8) {
-        if data & 0b1000_0000 != 0 {
-            byte_utils::set_carry(&mut self.status);
-        } else {
-            byte_utils::unset_carry(&mut self.status);
-        }
+        self.status.set(StatusFlags::CARRY, data & 0b1000_0000 != 0);
     }
 
     fn update_zero_flag(&mut self, result: u8) {
-        if result == 0 {
-            byte_utils::set_zero(&mut self.status);
-        } else {
-            byte_utils::unset_zero(&mut self.status);
-        }
+        self.status.set(StatusFlags::ZERO, result == 0);
     }
 
     fn update_negative_flag(&mut self, result: u8) {
-        if byte_utils::is_negative(result) {
-            byte_utils::set_negative(&mut self.status);
-        } else {
-            byte_utils::unset_negative(&mut self.status);
-        }
+        self.status.set(StatusFlags::NEGATIVE, result & 0b1000_0000 != 0);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    pub fn run(&mut self) -> Result<(), EmuError> {
+        self.run_with_callback(|_| {})
     }
 
     fn interrupt_nmi(&mut self) {
+        // Two reads of the opcode that gets skipped, then 3 pushes and the
+        // vector for 7 cycles in total
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
         self.push_stack_u16(self.program_counter);
-        let mut flag = self.status;
+        self.push_stack(self.status.to_stack(false));
+        self.status.insert(StatusFlags::INTERRUPT_DISABLE);
 
-        byte_utils::set_interrupt(&mut flag);
-        self.push_stack(flag);
-        byte_utils::set_interrupt_disable(&mut self.status);
-
-        self.bus.tick(2);
         self.program_counter = self.mem_read_u16(0xfffa)
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    // Same sequence as NMI through the IRQ/BRK vector, with B clear
+    fn interrupt_irq(&mut self) {
+        self.mem_read(self.program_counter);
+        self.mem_read(self.program_counter);
+        self.push_stack_u16(self.program_counter);
+        self.push_stack(self.status.to_stack(false));
+        self.status.insert(StatusFlags::INTERRUPT_DISABLE);
+
+        self.program_counter = self.mem_read_u16(0xFFFE)
+    }
+
+    // Only returns once something goes wrong, e.g. the CPU jams
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), EmuError>
     where
         F: FnMut(&mut CPU<T>),
     {
         loop {
-            self.step(&mut callback);
+            self.step(&mut callback)?;
         }
     }
 
-    pub fn step<F>(&mut self, mut callback: F)
+    // Runs until the PPU moves on to the next frame
+    pub fn run_frame<F>(&mut self, mut callback: F) -> Result<(), EmuError>
     where
         F: FnMut(&mut CPU<T>),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        let frame = self.bus.ppu_timing().ok_or(EmuError::NoPpu)?.frame;
+        while self.bus.ppu_timing().is_some_and(|timing| timing.frame == frame) {
+            self.step(&mut callback)?;
+        }
+        Ok(())
+    }
+
+    pub fn step<F>(&mut self, mut callback: F) -> Result<(), EmuError>
+    where
+        F: FnMut(&mut CPU<T>),
+    {
+        // A jammed CPU stops fetching but the clock keeps running
+        if self.halted {
+            self.bus.tick(1);
+            return Err(EmuError::CpuJammed(self.program_counter.wrapping_sub(1)));
+        }
+
         if let Some(_nmi) = self.bus.poll_nmi_status() {
-            // println!("Interrupting NMI");
+            verbose!(target: "cpu", "NMI at {:04X}", self.program_counter);
             self.interrupt_nmi();
-            // println!("Finished NMI");
+        } else if self.bus.irq_pending() && !self.status.contains(StatusFlags::INTERRUPT_DISABLE) {
+            verbose!(target: "cpu", "IRQ at {:04X}", self.program_counter);
+            self.interrupt_irq();
         }
-        // println!("Started tracing");
         callback(self);
-        // println!("Finished tracing");
         let code = self.mem_read(self.program_counter);
-        self.program_counter += 1;
+        self.program_counter = self.program_counter.wrapping_add(1);
         let program_counter_state = self.program_counter;
 
-        let opcode = opcodes
-            .get(&code)
-            .expect(&format!("OpCode {:x} is not recognized", code));
+        let opcode = opcodes::OPCODE_TABLE[code as usize]
+            .ok_or(EmuError::UnknownOpcode(code))?;
 
-        // println!("Running CPU");
-        match opcode.mneumonic {
-            "LDA" => {
+        // Implied and accumulator instructions still read the byte after
+        // the opcode on their second cycle
+        if let AddressingMode::NoneAddressing = opcode.mode {
+            self.mem_read(self.program_counter);
+        }
+
+        match opcode.instruction {
+            Instruction::LDA => {
                 self.lda(&opcode.mode);
             }
-            "LAX" => {
+            Instruction::LAX => {
                 self.lax(&opcode.mode);
             }
 
-            "LAS" => {
+            Instruction::LAS => {
                 self.las(&opcode.mode);
             }
 
-            "LDX" => {
+            Instruction::LDX => {
                 self.ldx(&opcode.mode);
             }
-            "LDY" => {
+            Instruction::LDY => {
                 self.ldy(&opcode.mode);
             }
 
-            "STA" => {
+            Instruction::STA => {
                 self.sta(&opcode.mode);
             }
 
-            "STX" => {
+            Instruction::STX => {
                 self.stx(&opcode.mode);
             }
 
-            "SAX" => {
+            Instruction::SAX => {
                 self.sax(&opcode.mode);
             }
 
-            "STY" => {
+            Instruction::STY => {
                 self.sty(&opcode.mode);
             }
 
             /* Register Instructions */
-            "TAX" => {
+            Instruction::TAX => {
                 self.tax();
             }
 
-            "TXA" => {
+            Instruction::TXA => {
                 self.txa();
             }
 
-            "TAY" => {
+            Instruction::TAY => {
                 self.tay();
             }
 
-            "TYA" => {
+            Instruction::TYA => {
                 self.tya();
             }
 
-            "INX" => {
+            Instruction::INX => {
                 self.inx();
             }
-            "DEX" => {
+            Instruction::DEX => {
                 self.dex();
             }
 
-            "INY" => {
+            Instruction::INY => {
                 self.iny();
             }
-            "DEY" => {
+            Instruction::DEY => {
                 self.dey();
             }
 
             /* Logical Operations */
-            "ASL A" => {
+            Instruction::ASL_A => {
                 self.asl_accumulator();
             }
 
-            "ASL" => {
+            Instruction::ASL => {
                 self.asl(&opcode.mode);
             }
 
-            "LSR A" => {
+            Instruction::LSR_A => {
                 self.lsr_accumulator();
             }
 
-            "LSR" => {
+            Instruction::LSR => {
                 self.lsr(&opcode.mode);
             }
 
-            "ROL A" => {
+            Instruction::ROL_A => {
                 self.rol_accumulator();
             }
 
-            "ROL" => {
+            Instruction::ROL => {
                 self.rol(&opcode.mode);
             }
 
-            "ROR A" => {
+            Instruction::ROR_A => {
                 self.ror_accumulator();
             }
 
-            "ROR" => {
+            Instruction::ROR => {
                 self.ror(&opcode.mode);
             }
 
             /* BITWISE */
-            "AND" => {
+            Instruction::AND => {
                 self.and(&opcode.mode);
             }
 
-            "ORA" => {
+            Instruction::ORA => {
                 self.or(&opcode.mode);
             }
 
-            "EOR" => {
+            Instruction::EOR => {
                 self.eor(&opcode.mode);
             }
 
-            "BIT" => {
+            Instruction::BIT => {
                 self.bit(&opcode.mode);
             }
 
-            "SLO" => {
+            Instruction::SLO => {
                 self.slo(&opcode.mode);
             }
 
-            "RLA" => {
+            Instruction::RLA => {
                 self.rla(&opcode.mode);
             }
 
-            "SRE" => {
+            Instruction::SRE => {
                 self.sre(&opcode.mode);
             }
 
-            "RRA" => {
+            Instruction::RRA => {
                 self.rra(&opcode.mode);
             }
 
             /* Compare X and Y */
-            "CMP" => {
+            Instruction::CMP => {
                 self.compare(&opcode.mode, self.register_a);
             }
 
-            "CPX" => {
+            Instruction::CPX => {
                 self.compare(&opcode.mode, self.register_x);
             }
 
-            "CPY" => {
+            Instruction::CPY => {
                 self.compare(&opcode.mode, self.register_y);
             }
 
             /* Flag Management */
-            "SEC" => {
-                byte_utils::set_carry(&mut self.status);
+            Instruction::SEC => {
+                self.status.insert(StatusFlags::CARRY);
             }
-            "SED" => {
-                byte_utils::set_decimal(&mut self.status);
+            Instruction::SED => {
+                self.status.insert(StatusFlags::DECIMAL);
             }
-            "SEI" => {
-                byte_utils::set_interrupt_disable(&mut self.status);
+            Instruction::SEI => {
+                self.status.insert(StatusFlags::INTERRUPT_DISABLE);
             }
 
-            "CLC" => {
-                byte_utils::unset_carry(&mut self.status);
+            Instruction::CLC => {
+                self.status.remove(StatusFlags::CARRY);
             }
-            "CLD" => {
-                byte_utils::unset_decimal(&mut self.status);
+            Instruction::CLD => {
+                self.status.remove(StatusFlags::DECIMAL);
             }
-            "CLI" => {
-                byte_utils::unset_interrupt_disable(&mut self.status);
+            Instruction::CLI => {
+                self.status.remove(StatusFlags::INTERRUPT_DISABLE);
             }
-            "CLV" => {
-                byte_utils::unset_overflow(&mut self.status);
+            Instruction::CLV => {
+                self.status.remove(StatusFlags::OVERFLOW);
             }
 
             /* Arithmetic */
-            "ADC" => {
+            Instruction::ADC => {
                 self.adc_sbc(&opcode.mode, false);
             }
-            "SBC" => {
+            Instruction::SBC => {
                 self.adc_sbc(&opcode.mode, true);
             }
 
             /* Branch */
-            "BNE" => {
-                self.branch(byte_utils::is_zero_set(self.status) == false);
+            Instruction::BNE => {
+                self.branch(!self.status.contains(StatusFlags::ZERO));
             }
-            "BEQ" => {
-                self.branch(byte_utils::is_zero_set(self.status) == true);
+            Instruction::BEQ => {
+                self.branch(self.status.contains(StatusFlags::ZERO));
             }
 
-            "BCC" => {
-                self.branch(byte_utils::is_carry_set(self.status) == false);
+            Instruction::BCC => {
+                self.branch(!self.status.contains(StatusFlags::CARRY));
             }
-            "BCS" => {
-                self.branch(byte_utils::is_carry_set(self.status) == true);
+            Instruction::BCS => {
+                self.branch(self.status.contains(StatusFlags::CARRY));
             }
 
-            "BMI" => {
-                self.branch(byte_utils::is_negative_set(self.status) == true);
+            Instruction::BMI => {
+                self.branch(self.status.contains(StatusFlags::NEGATIVE));
             }
-            "BPL" => {
-                self.branch(byte_utils::is_negative_set(self.status) == false);
+            Instruction::BPL => {
+                self.branch(!self.status.contains(StatusFlags::NEGATIVE));
             }
 
-            "BVC" => {
-                self.branch(byte_utils::is_overflow_set(self.status) == false);
+            Instruction::BVC => {
+                self.branch(!self.status.contains(StatusFlags::OVERFLOW));
             }
-            "BVS" => {
-                self.branch(byte_utils::is_overflow_set(self.status) == true);
+            Instruction::BVS => {
+                self.branch(self.status.contains(StatusFlags::OVERFLOW));
             }
 
-            "JMP" => {
+            Instruction::JMP => {
                 self.jmp(&opcode.mode);
             }
 
-            "JSR" => {
-                self.jsr(&opcode.mode);
+            Instruction::JSR => {
+                self.jsr();
             }
 
-            "RTS" => {
+            Instruction::RTS => {
                 self.rts();
             }
 
             /* Stack Operations */
-            "PHA" => {
+            Instruction::PHA => {
                 self.pha();
             }
-            "PLA" => {
+            Instruction::PLA => {
                 self.pla();
             }
 
-            "TXS" => {
+            Instruction::TXS => {
                 self.txs();
             }
-            "TSX" => {
+            Instruction::TSX => {
                 self.tsx();
             }
 
-            "PHP" => {
+            Instruction::PHP => {
                 self.php();
             }
-            "PLP" => {
+            Instruction::PLP => {
                 self.plp();
             }
-            "RTI" => {
+            Instruction::RTI => {
                 self.rti();
             }
 
             /* Memory */
-            "INC" => {
+            Instruction::INC => {
                 self.inc(&opcode.mode);
             }
-            "DEC" => {
+            Instruction::DEC => {
                 self.dec(&opcode.mode);
             }
 
-            "DCP" => {
+            Instruction::DCP => {
                 self.dcp(&opcode.mode);
             }
 
-            "ISB" => {
+            Instruction::ISB => {
                 self.isb(&opcode.mode);
             }
 
-            "AHX" => {
+            Instruction::AHX => {
                 self.ahx(&opcode.mode);
             }
 
-            "SHY" => {
+            Instruction::SHY => {
                 self.shy(&opcode.mode);
             }
 
-            "SHX" => {
+            Instruction::SHX => {
                 self.shx(&opcode.mode);
             }
 
-            "NOP" => {
-                if opcode.len == 3 {
-                    let (_, page_cross) = self.get_operand_address(&opcode.mode);
-                    if page_cross {
-                        self.bus.tick(1);
-                    }
+            Instruction::TAS => {
+                self.tas(&opcode.mode);
+            }
+
+            /* Immediate combos */
+            Instruction::ANC => {
+                self.anc(&opcode.mode);
+            }
+            Instruction::ALR => {
+                self.alr(&opcode.mode);
+            }
+            Instruction::ARR => {
+                self.arr(&opcode.mode);
+            }
+            Instruction::AXS => {
+                self.axs(&opcode.mode);
+            }
+            Instruction::ANE => {
+                self.ane(&opcode.mode);
+            }
+            Instruction::LXA => {
+                self.lxa(&opcode.mode);
+            }
+
+            Instruction::KIL => {
+                log::warn!(target: "cpu", "CPU jammed by {:02X} at {:04X}", code, program_counter_state.wrapping_sub(1));
+                self.halted = true;
+                return Err(EmuError::CpuJammed(program_counter_state.wrapping_sub(1)));
+            }
+
+            Instruction::NOP => {
+                if opcode.len > 1 {
+                    let addr = self.read_address(&opcode.mode);
+                    self.mem_read(addr);
                 }
             }
 
             /* Break */
-            "BRK" => {
+            Instruction::BRK => {
                 self.brk();
             }
 
-            _ => todo!(),
         }
 
-        self.bus.tick(opcode.cycles);
-
         if program_counter_state == self.program_counter {
             self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
         }
 
-        // println!("Running callback");
         // callback(self);
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::mock_rom;
+
+    // Save RAM is the only writable place with a mock cartridge
+    fn cpu_with(code: &[u8]) -> CPU<Bus<'static>> {
+        let mut cpu = CPU::new(Bus::empty_bus());
+        for (i, byte) in code.iter().enumerate() {
+            cpu.bus.mem_write(0x6000 + i as u16, *byte);
+        }
+        cpu.program_counter = 0x6000;
+        cpu
+    }
+
+    #[test]
+    fn cycles_match_the_opcode_table() {
+        for opcode in opcodes::CPU_OPS_CODES.iter() {
+            if let AddressingMode::Relative = opcode.mode {
+                continue;
+            }
+            // Every operand and pointer is zero, so nothing crosses a page
+            let mut cpu = cpu_with(&[opcode.code, 0x00, 0x00]);
+            let before = cpu.bus.cycles();
+            if let Err(err) = cpu.step(|_| {}) {
+                assert_eq!(opcode.instruction, Instruction::KIL, "{}", err);
+            }
+            assert_eq!(
+                cpu.bus.cycles() - before,
+                opcode.cycles as usize,
+                "{:02X} {}",
+                opcode.code,
+                opcode.instruction
+            );
+        }
+    }
+
+    #[test]
+    fn kil_halts_the_cpu() {
+        let mut cpu = cpu_with(&[0x02, 0xEA]);
+        assert_eq!(cpu.step(|_| {}), Err(EmuError::CpuJammed(0x6000)));
+        assert!(cpu.halted);
+        assert_eq!(cpu.program_counter, 0x6001);
+
+        let cycles = cpu.bus.cycles();
+        assert_eq!(cpu.step(|_| {}), Err(EmuError::CpuJammed(0x6000)));
+        assert_eq!(cpu.program_counter, 0x6001);
+        assert_eq!(cpu.bus.cycles(), cycles + 1);
+    }
+
+    #[test]
+    fn pc_wraps_after_an_opcode_at_ffff() {
+        let mut prg = vec![0; 0x4000];
+        // NOP at $FFFF
+        prg[0x3FFF] = 0xEA;
+        let mut cpu = CPU::new(Bus::new(mock_rom(prg), |_| {}));
+        cpu.program_counter = 0xFFFF;
+        cpu.step(|_| {}).unwrap();
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn run_frame_stops_at_the_next_frame() {
+        // JMP $6000
+        let mut cpu = cpu_with(&[0x4C, 0x00, 0x60]);
+        cpu.run_frame(|_| {}).unwrap();
+        assert_eq!(cpu.bus.ppu_timing().unwrap().frame, 1);
+        // 262 scanlines of 341 dots, 3 dots per CPU cycle
+        assert!((29780..29784).contains(&cpu.bus.cycles()));
+    }
+
+    #[test]
+    fn writes_to_rom_and_ppustatus_are_ignored() {
+        // LDA #$55, STA $8000, STA $2002
+        let mut cpu = cpu_with(&[0xA9, 0x55, 0x8D, 0x00, 0x80, 0x8D, 0x02, 0x20]);
+        for _ in 0..3 {
+            cpu.step(|_| {}).unwrap();
+        }
+        assert_eq!(cpu.bus.mem_read(0x8000), 0x00);
+        assert_eq!(cpu.program_counter, 0x6008);
+    }
+
+    #[test]
+    fn ane_and_lxa_use_the_magic_constant() {
+        let mut cpu = cpu_with(&[0x8B, 0xFF, 0xAB, 0x0F]);
+        cpu.register_a = 0x01;
+        cpu.register_x = 0x33;
+        cpu.step(|_| {}).unwrap();
+        assert_eq!(cpu.register_a, (0x01 | UNSTABLE_MAGIC) & 0x33);
+
+        cpu.unstable_magic = 0xFF;
+        cpu.step(|_| {}).unwrap();
+        assert_eq!(cpu.register_a, 0x0F);
+        assert_eq!(cpu.register_x, 0x0F);
+    }
+
+    #[test]
+    fn arr_sets_carry_and_overflow_from_bits_6_and_5() {
+        let mut cpu = cpu_with(&[0x6B, 0xC0]);
+        cpu.register_a = 0xFF;
+        cpu.status.insert(StatusFlags::CARRY);
+        cpu.step(|_| {}).unwrap();
+        // (0xC0 >> 1) | carry in
+        assert_eq!(cpu.register_a, 0xE0);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+        assert!(!cpu.status.contains(StatusFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn axs_subtracts_from_a_and_x() {
+        let mut cpu = cpu_with(&[0xCB, 0x02]);
+        cpu.register_a = 0x0F;
+        cpu.register_x = 0x03;
+        cpu.step(|_| {}).unwrap();
+        assert_eq!(cpu.register_x, 0x01);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn shx_page_cross_corrupts_the_address() {
+        // SHX $60F0,Y with Y = $20 should land on $6110, but the stored
+        // X & ($60 + 1) = $60 also becomes the high byte
+        let mut cpu = cpu_with(&[0x9E, 0xF0, 0x60]);
+        cpu.register_x = 0x7E;
+        cpu.register_y = 0x20;
+        cpu.step(|_| {}).unwrap();
+        assert_eq!(cpu.bus.mem_read(0x6010), 0x60);
+        assert_eq!(cpu.bus.mem_read(0x6110), 0x00);
+    }
+}
@@ -0,0 +1,19 @@
+use derive_more::Display;
+
+// Conditions that stop emulation. Things real hardware shrugs off, like
+// writes to ROM or read-only registers, are ignored instead
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum EmuError {
+    // A KIL/JAM opcode locked up the CPU, only a reset gets it going again
+    #[display("CPU jammed at {_0:04X}")]
+    CpuJammed(u16),
+
+    #[display("OpCode {_0:02X} is not recognized")]
+    UnknownOpcode(u8),
+
+    // run_frame needs a PPU to know where frames end
+    #[display("The bus has no PPU to run frames with")]
+    NoPpu,
+}
+
+impl std::error::Error for EmuError {}
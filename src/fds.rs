@@ -0,0 +1,785 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::apu::NTSC_CPU_CLOCK;
+use crate::cartridge::{LoadError, Mirroring, archive, patch};
+use crate::logging::verbose;
+
+// .fds files are disk sides of 65500 bytes, optionally behind fwNES' 16
+// byte header. Sides hold the blocks without the gaps and CRCs the drive
+// sees, those are put back when a side is inserted
+const FDS_TAG: &[u8] = b"FDS\x1A";
+const FWNES_HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+pub const BIOS_SIZE: usize = 8192;
+const RAM_SIZE: usize = 0x8000;
+pub const CHR_RAM_SIZE: usize = 8192;
+
+// Block 1 starts every side
+const DISK_INFO: &[u8] = b"\x01*NINTENDO-HVC*";
+const DISK_INFO_SIZE: usize = 56;
+const FILE_AMOUNT_SIZE: usize = 2;
+const FILE_HEADER_SIZE: usize = 16;
+
+// Gaps in bytes, from the 28300 and 976 bit gaps on real disks
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// Ends a gap, the block follows
+const GAP_END: u8 = 0x80;
+// .fds files drop the CRCs and the BIOS doesn't get to check them
+const FAKE_CRC: [u8; 2] = [0x4D, 0x62];
+
+// Drive timing in CPU cycles
+const HEAD_RETURN_DELAY: u32 = 50000;
+const BYTE_DELAY: u32 = 150;
+// How long switch_side leaves the drive empty, the BIOS has to notice
+const SWAP_DELAY: u32 = NTSC_CPU_CLOCK as u32;
+
+// Length of the block starting at `data`, `file_size` comes from the last
+// file header. None once the blocks run out
+fn block_size(data: &[u8], file_size: usize) -> Option<usize> {
+    let size = match data.first()? {
+        1 => DISK_INFO_SIZE,
+        2 => FILE_AMOUNT_SIZE,
+        3 => FILE_HEADER_SIZE,
+        4 => 1 + file_size,
+        _ => return None,
+    };
+    (size <= data.len()).then_some(size)
+}
+
+fn file_size(file_header: &[u8]) -> usize {
+    u16::from_le_bytes([file_header[13], file_header[14]]) as usize
+}
+
+// What the head passes over: a lead-in, then every block after a gap with
+// its CRC behind it. The unused rest of the side stays blank
+fn to_raw(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN];
+    let mut pos = 0;
+    let mut file = 0;
+    while let Some(size) = block_size(&side[pos..], file) {
+        let block = &side[pos..pos + size];
+        if block[0] == 3 {
+            file = file_size(block);
+        }
+        raw.push(GAP_END);
+        raw.extend(block);
+        raw.extend(FAKE_CRC);
+        raw.extend([0; BLOCK_GAP]);
+        pos += size;
+    }
+    raw.resize(raw.len() + side.len() - pos, 0);
+    raw
+}
+
+// Undoes to_raw, picking up blocks the BIOS wrote since
+fn from_raw(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut pos = 0;
+    let mut file = 0;
+    loop {
+        while raw.get(pos) == Some(&0) {
+            pos += 1;
+        }
+        if raw.get(pos) != Some(&GAP_END) {
+            break;
+        }
+        pos += 1;
+        let Some(size) = block_size(&raw[pos..], file) else {
+            break;
+        };
+        let block = &raw[pos..pos + size];
+        if block[0] == 3 {
+            file = file_size(block);
+        }
+        side.extend(block);
+        pos += size + FAKE_CRC.len();
+    }
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+    // The file as loaded, diffs are made against it
+    original: Vec<u8>,
+    header: bool,
+    diff_path: Option<PathBuf>,
+}
+
+impl FdsImage {
+    pub fn new(raw: &[u8]) -> Result<FdsImage, String> {
+        let header = raw.starts_with(FDS_TAG);
+        let data = if header { &raw[FWNES_HEADER_SIZE.min(raw.len())..] } else { raw };
+        if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+            return Err(format!("FDS image isn't made of {} byte disk sides", SIDE_SIZE));
+        }
+
+        let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(<[u8]>::to_vec).collect();
+        if let Some(side) = sides.iter().position(|side| !side.starts_with(DISK_INFO)) {
+            return Err(format!("Disk side {} has no disk info block", side + 1));
+        }
+        Ok(FdsImage {
+            sides,
+            original: raw.to_vec(),
+            header,
+            diff_path: None,
+        })
+    }
+
+    // `game.fds`, or an archive whose `entry` (the first image when None)
+    // is one
+    pub fn is_disk_image(path: &Path, entry: Option<&str>) -> bool {
+        let is_fds = |name: &str| name.to_ascii_lowercase().ends_with(".fds");
+        if !archive::is_archive(path) {
+            return is_fds(&path.to_string_lossy());
+        }
+        match entry {
+            Some(entry) => is_fds(entry),
+            None => archive::rom_entries(path)
+                .ok()
+                .and_then(|entries| entries.into_iter().next())
+                .is_some_and(|name| is_fds(&name)),
+        }
+    }
+
+    // Writes to `game.fds` are kept in `game.fds.ips`. IPS patches don't
+    // check what they're applied to, so `disk.fds` inside `games.zip` gets
+    // `games.zip.disk.fds.ips` and the other disks in the zip can't pick up
+    // its writes
+    pub fn diff_path(path: &Path, entry: Option<&str>) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        if let Some(entry) = entry.and_then(|entry| Path::new(entry).file_name()) {
+            name.push(".");
+            name.push(entry);
+        }
+        name.push(".ips");
+        path.with_file_name(name)
+    }
+
+    // Loads the image with the writes from earlier runs on top
+    pub fn from_path(path: &Path) -> Result<FdsImage, LoadError> {
+        FdsImage::from_path_entry(path, None)
+    }
+
+    // Picks `entry` when `path` is a zip file with more than one image
+    pub fn from_path_entry(path: &Path, entry: Option<&str>) -> Result<FdsImage, LoadError> {
+        let entry = if archive::is_zip(path) {
+            Some(archive::entry_name(path, entry)?)
+        } else {
+            None
+        };
+        let raw = if archive::is_archive(path) {
+            archive::read(path, entry.as_deref())?
+        } else {
+            fs::read(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?
+        };
+        let mut image = FdsImage::new(&raw).map_err(|err| LoadError::Rom(path.to_path_buf(), err))?;
+
+        let diff_path = FdsImage::diff_path(path, entry.as_deref());
+        if let Ok(diff) = fs::read(&diff_path) {
+            let patched = patch::apply(&diff, &raw).map_err(|err| LoadError::Patch(diff_path.clone(), err))?;
+            image.sides = FdsImage::new(&patched)
+                .map_err(|err| LoadError::Rom(diff_path.clone(), err))?
+                .sides;
+        }
+        image.diff_path = Some(diff_path);
+        Ok(image)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.header {
+            bytes.extend(FDS_TAG);
+            bytes.push(self.sides.len() as u8);
+            bytes.resize(FWNES_HEADER_SIZE, 0);
+        }
+        for side in &self.sides {
+            bytes.extend(side);
+        }
+        bytes
+    }
+
+    // Saves the writes next to the image, which is never touched
+    pub fn save_diff(&self) -> io::Result<()> {
+        let Some(path) = &self.diff_path else {
+            return Ok(());
+        };
+        let bytes = self.to_bytes();
+        if bytes != self.original || path.exists() {
+            fs::write(path, patch::create_ips(&self.original, &bytes))?;
+        }
+        Ok(())
+    }
+}
+
+// The RAM adapter: 32K PRG RAM at $6000-$DFFF, the BIOS at $E000-$FFFF, the
+// IRQ timer and the disk drive behind $4020-$4033. The sound channel at
+// $4040-$4092 isn't emulated
+pub struct Fds {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    image: FdsImage,
+    raw_sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    pending_side: Option<(usize, u32)>,
+    written: bool,
+
+    // $4020-$4023
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    disk_io_enabled: bool,
+
+    // $4024-$4025
+    write_data: u8,
+    read_data: u8,
+    motor_on: bool,
+    transfer_reset: bool,
+    read_mode: bool,
+    crc_control: bool,
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    mirroring: Mirroring,
+
+    // The drive
+    head: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_done: bool,
+    disk_irq: bool,
+}
+
+impl Fds {
+    // Side 1 starts out inserted
+    pub fn new(bios: Vec<u8>, image: FdsImage) -> Result<Fds, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!("The FDS BIOS is {} bytes, not {}", BIOS_SIZE, bios.len()));
+        }
+        let raw_sides = image.sides.iter().map(|side| to_raw(side)).collect();
+        Ok(Fds {
+            bios,
+            ram: vec![0; RAM_SIZE],
+            image,
+            raw_sides,
+            side: Some(0),
+            pending_side: None,
+            written: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_io_enabled: false,
+            write_data: 0,
+            read_data: 0,
+            motor_on: false,
+            transfer_reset: false,
+            read_mode: true,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            mirroring: Mirroring::HORIZONTAL,
+            head: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_done: false,
+            disk_irq: false,
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.raw_sides.len()
+    }
+
+    // 0 based, None while the drive is empty
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.pending_side = None;
+    }
+
+    pub fn insert(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.side_count() {
+            return Err(format!("The disk has no side {}", side + 1));
+        }
+        self.side = Some(side);
+        self.pending_side = None;
+        self.end_of_head = true;
+        Ok(())
+    }
+
+    // Ejects and inserts the next side a second later, what a player would
+    // do when the game asks for side B
+    pub fn switch_side(&mut self) {
+        let next = self.side.map_or(0, |side| (side + 1) % self.side_count());
+        self.side = None;
+        self.pending_side = Some((next, SWAP_DELAY));
+    }
+
+    // The image with everything written to the disk so far
+    pub fn image(&mut self) -> &FdsImage {
+        if self.written {
+            self.image.sides = self.raw_sides.iter().map(|raw| from_raw(raw)).collect();
+            self.written = false;
+        }
+        &self.image
+    }
+
+    pub fn save_diff(&mut self) -> io::Result<()> {
+        self.image().save_diff()
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring.clone()
+    }
+
+    pub fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    // None for addresses the adapter leaves to the rest of the bus
+    pub fn read(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek(addr)?;
+        match addr {
+            0x4030 => {
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.transfer_done = false;
+            }
+            0x4031 => {
+                self.transfer_done = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        Some(data)
+    }
+
+    // `read` without acknowledging anything, for the trace logger
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        let data = match addr {
+            0x4030 => {
+                self.timer_irq as u8 | (self.transfer_done as u8) << 1 | (self.end_of_head as u8) << 6
+            }
+            0x4031 => self.read_data,
+            0x4032 => {
+                let empty = self.side.is_none();
+                // The upper bits are open bus, $40 from the address
+                0x40 | empty as u8 | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2
+            }
+            // Battery good on the expansion port
+            0x4033 => 0x80,
+            0x6000..=0xDFFF => self.ram[(addr - 0x6000) as usize],
+            0xE000..=0xFFFF => self.bios[(addr - 0xE000) as usize],
+            _ => return None,
+        };
+        Some(data)
+    }
+
+    // False for addresses the adapter leaves to the rest of the bus
+    pub fn write(&mut self, addr: u16, data: u8) -> bool {
+        match addr {
+            0x4020 => self.timer_reload = self.timer_reload & 0xFF00 | data as u16,
+            0x4021 => self.timer_reload = self.timer_reload & 0x00FF | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0b01 != 0;
+                self.timer_enabled = data & 0b10 != 0 && self.disk_io_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = data & 0b01 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 | 0x4025 if !self.disk_io_enabled => {
+                verbose!(target: "mapper", "Ignoring FDS write with disk I/O off: {:04X} = {:02X}", addr, data);
+            }
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_done = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = data & 0b0000_0001 != 0;
+                self.transfer_reset = data & 0b0000_0010 != 0;
+                self.read_mode = data & 0b0000_0100 != 0;
+                self.mirroring = if data & 0b0000_1000 != 0 {
+                    Mirroring::HORIZONTAL
+                } else {
+                    Mirroring::VERTICAL
+                };
+                self.crc_control = data & 0b0001_0000 != 0;
+                self.transfer_enabled = data & 0b0100_0000 != 0;
+                self.disk_irq_enabled = data & 0b1000_0000 != 0;
+                self.disk_irq = false;
+            }
+            0x4026 => {}
+            0x6000..=0xDFFF => self.ram[(addr - 0x6000) as usize] = data,
+            0xE000..=0xFFFF => {
+                verbose!(target: "mapper", "Ignoring write to the FDS BIOS: {:04X} = {:02X}", addr, data);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+        }
+        if let Some((side, delay)) = self.pending_side {
+            match delay.checked_sub(cycles as u32) {
+                Some(delay) if delay > 0 => self.pending_side = Some((side, delay)),
+                _ => {
+                    self.pending_side = None;
+                    self.side = Some(side);
+                    self.end_of_head = true;
+                }
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            self.timer_enabled = self.timer_repeat;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    // One byte passes under the head every BYTE_DELAY cycles once the motor
+    // is on. At the end of the disk the motor stops and the head goes back
+    fn clock_drive(&mut self) {
+        let Some(side) = self.side else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.transfer_reset && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_DELAY;
+            self.end_of_head = false;
+            self.head = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let raw = &mut self.raw_sides[side];
+        if self.read_mode {
+            let data = raw[self.head];
+            let mut irq = self.disk_irq_enabled;
+            if !self.transfer_enabled {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The end of gap mark is read without an IRQ
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_done = true;
+                self.read_data = data;
+                self.disk_irq |= irq;
+            }
+        } else {
+            if !self.crc_control {
+                self.transfer_done = true;
+                self.disk_irq |= self.disk_irq_enabled;
+            }
+            // Gap while transfers are off, the BIOS writes the end mark
+            raw[self.head] = match (self.transfer_enabled, self.crc_control) {
+                (false, _) => 0,
+                (true, true) => FAKE_CRC[0],
+                (true, false) => self.write_data,
+            };
+            self.gap_ended = false;
+            self.written = true;
+        }
+
+        self.head += 1;
+        if self.head >= raw.len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::{Bus, BusOP};
+    use crate::cpu::{CPU, Mem};
+    use crate::trace::trace;
+    use std::io::Write;
+    use zip::write::{SimpleFileOptions, ZipWriter};
+
+    // One side with a single 4 byte file
+    fn side() -> Vec<u8> {
+        let mut side = DISK_INFO.to_vec();
+        side.resize(DISK_INFO_SIZE, 0);
+        side.extend([2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.extend(b"FILE0000");
+        header.extend([0x00, 0x60, 4, 0, 0]);
+        side.extend(header);
+        side.extend([4, 0xA, 0xB, 0xC, 0xD]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn image_file(header: bool, sides: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        if header {
+            raw.extend(FDS_TAG);
+            raw.push(sides as u8);
+            raw.resize(FWNES_HEADER_SIZE, 0);
+        }
+        for _ in 0..sides {
+            raw.extend(side());
+        }
+        raw
+    }
+
+    #[test]
+    fn parses_images_with_and_without_header() {
+        let image = FdsImage::new(&image_file(true, 2)).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.to_bytes(), image_file(true, 2));
+
+        let image = FdsImage::new(&image_file(false, 1)).unwrap();
+        assert_eq!(image.sides.len(), 1);
+        assert_eq!(image.to_bytes(), image_file(false, 1));
+
+        assert!(FdsImage::new(&[0; 100]).is_err());
+        assert_eq!(
+            FdsImage::new(&[0; SIDE_SIZE]).unwrap_err(),
+            "Disk side 1 has no disk info block"
+        );
+    }
+
+    #[test]
+    fn raw_sides_round_trip() {
+        let side = side();
+        let raw = to_raw(&side);
+        assert_eq!(raw[LEAD_IN], GAP_END);
+        assert_eq!(&raw[LEAD_IN + 1..LEAD_IN + 1 + DISK_INFO.len()], DISK_INFO);
+        assert_eq!(from_raw(&raw), side);
+    }
+
+    #[test]
+    fn timer_raises_the_irq() {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], FdsImage::new(&image_file(false, 1)).unwrap()).unwrap();
+        fds.write(0x4020, 10);
+        fds.write(0x4022, 0b10);
+        // Disk I/O is off
+        fds.tick(20);
+        assert!(!fds.irq());
+
+        fds.write(0x4023, 1);
+        fds.write(0x4022, 0b10);
+        fds.tick(10);
+        assert!(!fds.irq());
+        fds.tick(1);
+        assert!(fds.irq());
+        assert_eq!(fds.read(0x4030), Some(1 | 0x40));
+        assert!(!fds.irq());
+        // Not repeating
+        fds.tick(100);
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn timer_irq_reaches_the_cpu() {
+        let mut bios = vec![0; BIOS_SIZE];
+        // CLI, then spin until the IRQ lands at $E100 on an LDA $4030
+        bios[..4].copy_from_slice(&[0x58, 0x4C, 0x01, 0xE0]);
+        bios[0x100..0x103].copy_from_slice(&[0xAD, 0x30, 0x40]);
+        bios[0x1FFC..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE1]);
+        let fds = Fds::new(bios, FdsImage::new(&image_file(false, 1)).unwrap()).unwrap();
+
+        let mut cpu = CPU::new(Bus::with_fds(fds, |_| {}));
+        cpu.reset();
+        cpu.bus.mem_write(0x4023, 1);
+        cpu.bus.mem_write(0x4020, 100);
+        cpu.bus.mem_write(0x4022, 0b10);
+        // Tracing shows the status without acknowledging the IRQ
+        let mut traced = None;
+        while cpu.bus.cycles() < 200 && traced.is_none() {
+            cpu.step(|cpu| {
+                if cpu.program_counter == 0xE100 {
+                    traced = Some((trace(cpu), cpu.bus.irq_pending()));
+                }
+            })
+            .unwrap();
+        }
+        let (line, pending) = traced.unwrap();
+        assert!(line.contains("LDA $4030 = 41"), "{}", line);
+        assert!(pending);
+        assert!(!cpu.bus.irq_pending());
+    }
+
+    #[test]
+    fn drive_reads_blocks_after_the_gap() {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], FdsImage::new(&image_file(false, 1)).unwrap()).unwrap();
+        assert_eq!(fds.read(0x4032), Some(0x42));
+
+        fds.write(0x4023, 1);
+        // Motor on, read mode, transfers and their IRQs on
+        fds.write(0x4025, 0b1100_0101);
+        let mut bytes = Vec::new();
+        while bytes.len() < DISK_INFO.len() + 1 {
+            fds.tick(1);
+            if fds.transfer_done {
+                bytes.push(fds.read(0x4031).unwrap());
+            }
+        }
+        assert_eq!(bytes[0], GAP_END);
+        assert_eq!(&bytes[1..], DISK_INFO);
+        assert_eq!(fds.read(0x4032), Some(0x40));
+        assert_eq!(fds.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn drive_writes_gaps_and_data() {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], FdsImage::new(&image_file(false, 1)).unwrap()).unwrap();
+        fds.raw_sides[0][0] = 0xFF;
+        fds.write(0x4023, 1);
+        fds.write(0x4024, 0xAB);
+        // Motor on, write mode, transfers off: a gap byte
+        fds.write(0x4025, 0b0000_0001);
+        while !fds.transfer_done {
+            fds.tick(1);
+        }
+        assert_eq!(fds.raw_sides[0][0], 0);
+
+        fds.write(0x4025, 0b0100_0001);
+        fds.read(0x4030);
+        while !fds.transfer_done {
+            fds.tick(1);
+        }
+        assert_eq!(fds.raw_sides[0][1], 0xAB);
+        assert!(fds.written);
+    }
+
+    #[test]
+    fn motor_restarts_at_the_end_of_a_side() {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], FdsImage::new(&image_file(false, 1)).unwrap()).unwrap();
+        fds.write(0x4023, 1);
+        fds.write(0x4025, 0b0000_0101);
+        fds.tick(1);
+        // Skip ahead to the last byte
+        fds.head = fds.raw_sides[0].len() - 1;
+        fds.delay = 0;
+        fds.tick(1);
+        assert!(!fds.motor_on);
+        assert!(fds.end_of_head);
+
+        // The BIOS turns the motor back on right away, the head has to go
+        // back to the start first
+        fds.write(0x4025, 0b0000_0101);
+        fds.tick(1);
+        assert_eq!(fds.head, 0);
+        assert_eq!(fds.delay, HEAD_RETURN_DELAY);
+    }
+
+    #[test]
+    fn writes_end_up_in_the_diff() {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-fds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.fds");
+        fs::write(&path, image_file(true, 1)).unwrap();
+
+        let image = FdsImage::from_path(&path).unwrap();
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], image).unwrap();
+        // What the drive leaves behind after the BIOS rewrote the file
+        let raw = &mut fds.raw_sides[0];
+        let data = raw.windows(2).position(|pair| pair == [GAP_END, 4]).unwrap() + 2;
+        raw[data..data + 4].copy_from_slice(&[1, 2, 3, 4]);
+        fds.written = true;
+        fds.save_diff().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), image_file(true, 1));
+        let reloaded = FdsImage::from_path(&path).unwrap();
+        let offset = DISK_INFO_SIZE + 2 + 16;
+        assert_eq!(&reloaded.sides[0][offset..offset + 5], &[4, 1, 2, 3, 4]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loads_zipped_images() {
+        let dir = std::env::temp_dir().join(format!("nes-emulator-fds-zip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("game.zip");
+        let mut zip = ZipWriter::new(fs::File::create(&path).unwrap());
+        zip.start_file("game.fds", SimpleFileOptions::default()).unwrap();
+        zip.write_all(&image_file(true, 2)).unwrap();
+        zip.start_file("disks/other.fds", SimpleFileOptions::default()).unwrap();
+        zip.write_all(&image_file(true, 1)).unwrap();
+        zip.finish().unwrap();
+
+        assert!(FdsImage::is_disk_image(&path, None));
+        assert!(!FdsImage::is_disk_image(&path, Some("game.nes")));
+        let image = FdsImage::from_path(&path).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.diff_path, Some(dir.join("game.zip.game.fds.ips")));
+
+        // Each disk in the zip keeps its own writes
+        let other = FdsImage::from_path_entry(&path, Some("disks/other.fds")).unwrap();
+        assert_eq!(other.sides.len(), 1);
+        assert_eq!(other.diff_path, Some(dir.join("game.zip.other.fds.ips")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn switching_sides_leaves_the_drive_empty_for_a_while() {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], FdsImage::new(&image_file(false, 2)).unwrap()).unwrap();
+        fds.switch_side();
+        assert_eq!(fds.side(), None);
+        for _ in 0..=SWAP_DELAY / 100 {
+            fds.tick(100);
+        }
+        assert_eq!(fds.side(), Some(1));
+        assert!(fds.insert(2).is_err());
+    }
+}
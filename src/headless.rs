@@ -0,0 +1,192 @@
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::CPU;
+use crate::fds::Fds;
+use crate::joypad::JoypadButton;
+use crate::region::Region;
+use crate::render;
+use crate::render::frame::Frame;
+
+// Controller 1 input by frame. An entry holds its buttons from that frame
+// until the next entry, so "60:START" then "62:" taps START for two frames.
+//
+// Scripts have one `FRAME:BUTTONS` entry per line, buttons joined with `+`
+// (e.g. `120:A+RIGHT`). Empty lines and `#` comments are skipped.
+#[derive(Debug, Default, PartialEq)]
+pub struct InputScript {
+    entries: Vec<(usize, JoypadButton)>,
+}
+
+impl InputScript {
+    pub fn new() -> Self {
+        InputScript { entries: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (frame, buttons) =
+                parse_entry(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+            script.push(frame, buttons);
+        }
+        Ok(script)
+    }
+
+    // A later entry for the same frame replaces the earlier one
+    pub fn push(&mut self, frame: usize, buttons: JoypadButton) {
+        match self.entries.binary_search_by_key(&frame, |&(at, _)| at) {
+            Ok(index) => self.entries[index].1 = buttons,
+            Err(index) => self.entries.insert(index, (frame, buttons)),
+        }
+    }
+
+    pub fn entries(&self) -> &[(usize, JoypadButton)] {
+        &self.entries
+    }
+
+    pub fn buttons_at(&self, frame: usize) -> JoypadButton {
+        self.entries
+            .iter()
+            .take_while(|&&(at, _)| at <= frame)
+            .last()
+            .map_or(JoypadButton::empty(), |&(_, buttons)| buttons)
+    }
+}
+
+pub fn parse_entry(entry: &str) -> Result<(usize, JoypadButton), String> {
+    let (frame, names) = entry
+        .split_once(':')
+        .ok_or_else(|| format!("expected FRAME:BUTTONS, got {:?}", entry))?;
+    let frame = frame
+        .trim()
+        .parse()
+        .map_err(|_| format!("bad frame number {:?}", frame))?;
+
+    let mut buttons = JoypadButton::empty();
+    for name in names.split('+').map(str::trim).filter(|name| !name.is_empty()) {
+        buttons |= JoypadButton::from_name(name).ok_or_else(|| format!("unknown button {:?}", name))?;
+    }
+    Ok((frame, buttons))
+}
+
+// One console without a window, run a frame at a time
+pub struct Headless {
+    cpu: CPU<Bus<'static>>,
+    frame: Frame,
+}
+
+impl Headless {
+    pub fn new(rom: Rom) -> Result<Headless, String> {
+        if rom.header.mapper != 0 {
+            return Err(format!("Mapper {} is not supported", rom.header.mapper));
+        }
+
+        let mut cpu = CPU::new(Bus::new(rom, |_| {}));
+        cpu.reset();
+        Ok(Headless {
+            cpu,
+            frame: Frame::new(),
+        })
+    }
+
+    // A disk system with `fds` in the drive instead of a cartridge
+    pub fn with_fds(fds: Fds) -> Headless {
+        let mut cpu = CPU::new(Bus::with_fds(fds, |_| {}));
+        cpu.reset();
+        Headless {
+            cpu,
+            frame: Frame::new(),
+        }
+    }
+
+    pub fn fds(&mut self) -> Option<&mut Fds> {
+        self.cpu.bus.fds()
+    }
+
+    // Picked from the ROM's header, this overrides it
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu.bus.set_region(region);
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.bus.region()
+    }
+
+    // The reset button, RAM and the PPU keep their state
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    pub fn run_frame(&mut self, port0: JoypadButton, port1: JoypadButton) -> Result<&Frame, String> {
+        self.cpu.bus.joypad1().button_status = port0;
+        self.cpu.bus.joypad2().button_status = port1;
+        self.cpu.run_frame(|_| {}).map_err(|err| err.to_string())?;
+
+        render::render(self.cpu.bus.ppu(), &mut self.frame);
+        Ok(&self.frame)
+    }
+
+    pub fn into_frame(self) -> Frame {
+        self.frame
+    }
+}
+
+/// Runs `rom` without a window for `frames` frames, feeding controller 1
+/// from `input`. `on_frame` sees every rendered frame with its number and
+/// the last one is returned.
+pub fn run_headless<F>(rom: Rom, frames: usize, input: &InputScript, mut on_frame: F) -> Result<Frame, String>
+where
+    F: FnMut(usize, &Frame),
+{
+    let mut console = Headless::new(rom)?;
+    for number in 0..frames {
+        let frame = console.run_frame(input.buttons_at(number), JoypadButton::empty())?;
+        on_frame(number, frame);
+    }
+    Ok(console.into_frame())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_scripts() {
+        let script = InputScript::parse("# title screen\n60:START\n\n62: # let go\n120:A + right\n").unwrap();
+
+        assert_eq!(script.buttons_at(0), JoypadButton::empty());
+        assert_eq!(script.buttons_at(60), JoypadButton::START);
+        assert_eq!(script.buttons_at(61), JoypadButton::START);
+        assert_eq!(script.buttons_at(62), JoypadButton::empty());
+        assert_eq!(script.buttons_at(500), JoypadButton::A | JoypadButton::RIGHT);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        assert!(InputScript::parse("60 START").is_err());
+        assert!(InputScript::parse("x:START").is_err());
+        assert_eq!(
+            InputScript::parse("1:A\n2:TURBO").unwrap_err(),
+            "line 2: unknown button \"TURBO\""
+        );
+    }
+
+    #[test]
+    fn runs_are_deterministic() {
+        let bytes = std::fs::read("roms/pacman.nes").unwrap();
+        let mut input = InputScript::new();
+        input.push(5, JoypadButton::START);
+
+        let mut hashes = [Vec::new(), Vec::new()];
+        for hashes in hashes.iter_mut() {
+            let rom = Rom::new(&bytes).unwrap();
+            run_headless(rom, 10, &input, |_, frame| hashes.push(frame.hash())).unwrap();
+        }
+        assert_eq!(hashes[0].len(), 10);
+        assert_eq!(hashes[0], hashes[1]);
+    }
+}
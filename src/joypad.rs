@@ -0,0 +1,120 @@
+use bitflags::bitflags;
+
+bitflags! {
+    // Report order of the 4021 shift register, A comes out first
+    pub struct JoypadButton: u8 {
+        const RIGHT  = 0b1000_0000;
+        const LEFT   = 0b0100_0000;
+        const DOWN   = 0b0010_0000;
+        const UP     = 0b0001_0000;
+        const START  = 0b0000_1000;
+        const SELECT = 0b0000_0100;
+        const B      = 0b0000_0010;
+        const A      = 0b0000_0001;
+    }
+}
+
+impl JoypadButton {
+    pub fn from_name(name: &str) -> Option<JoypadButton> {
+        match name.to_ascii_uppercase().as_str() {
+            "RIGHT" => Some(JoypadButton::RIGHT),
+            "LEFT" => Some(JoypadButton::LEFT),
+            "DOWN" => Some(JoypadButton::DOWN),
+            "UP" => Some(JoypadButton::UP),
+            "START" => Some(JoypadButton::START),
+            "SELECT" => Some(JoypadButton::SELECT),
+            "B" => Some(JoypadButton::B),
+            "A" => Some(JoypadButton::A),
+            _ => None,
+        }
+    }
+}
+
+// Standard controller on $4016/$4017
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    pub button_status: JoypadButton,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::empty(),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        // After all 8 buttons an official controller keeps returning 1
+        if self.button_index > 7 {
+            return 1;
+        }
+        let response = (self.button_status.bits() >> self.button_index) & 1;
+        if !self.strobe {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_mode() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.set_button_pressed_status(JoypadButton::A, true);
+        for _ in 0..=10 {
+            assert_eq!(joypad.read(), 1);
+        }
+    }
+
+    #[test]
+    fn test_strobe_mode_on_off() {
+        let mut joypad = Joypad::new();
+
+        joypad.write(0);
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        joypad.set_button_pressed_status(JoypadButton::SELECT, true);
+        joypad.set_button_pressed_status(JoypadButton::B, true);
+
+        for _ in 0..=1 {
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 1);
+
+            for _ in 0..10 {
+                assert_eq!(joypad.read(), 1);
+            }
+            joypad.write(1);
+            joypad.write(0);
+        }
+    }
+}
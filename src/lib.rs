@@ -1,8 +1,19 @@
+pub mod apu;
 pub mod cpu;
+pub mod joypad;
+pub mod error;
+pub mod fds;
+pub mod headless;
+mod logging;
+pub mod movie;
+pub mod nsf;
 pub mod opcodes;
-pub mod byte_utils;
+pub mod region;
+pub mod status_flags;
 pub mod bus;
 pub mod cartridge;
 pub mod trace;
 pub mod ppu;
-pub mod render;
\ No newline at end of file
+pub mod render;
+pub mod symbols;
+pub mod test_rom;
@@ -0,0 +1,25 @@
+// Diagnostics go through the `log` facade with one target per subsystem:
+//   cpu, bus, ppu, apu, mapper
+// e.g. RUST_LOG=ppu=trace with env_logger.
+//
+// `verbose!` is for per-access messages. Without the `verbose-logging`
+// feature the arguments are still type checked but the call is dead code
+// the compiler removes, so the hot paths don't even test the log level.
+
+#[cfg(feature = "verbose-logging")]
+macro_rules! verbose {
+    ($($arg:tt)+) => {
+        log::trace!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "verbose-logging"))]
+macro_rules! verbose {
+    ($($arg:tt)+) => {
+        if false {
+            log::trace!($($arg)+)
+        }
+    };
+}
+
+pub(crate) use verbose;
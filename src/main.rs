@@ -1,18 +1,8 @@
-pub mod bus;
-pub mod byte_utils;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcodes;
-pub mod ppu;
-pub mod trace;
-pub mod render;
-
-use bus::BusOP;
-use cartridge::Rom;
-use cpu::CPU;
-use cpu::Mem;
-use render::frame::Frame;
-use render::palette;
+use nes_emulator::bus::BusOP;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::render::frame::Frame;
+use nes_emulator::render::palette;
 
 // use rand::Rng;
 use sdl2::EventPump;
@@ -30,6 +20,8 @@ const ROM: &str = "pacman.nes";
 
 #[allow(dead_code)]
 fn main() {
+    env_logger::init();
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
@@ -49,8 +41,7 @@ fn main() {
 
     let path = Path::new("roms/").join(ROM);
 
-    let bytes = std::fs::read(path).unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let rom = Rom::from_path(&path).unwrap();
 
     /*
     let mut frame = Frame::new();
@@ -128,7 +119,7 @@ fn main() {
         // println!("{}", trace(cpu));
         println!(trace(cpu));
         handle_user_input(cpu,&mut event_pump);
-        cpu.mem_write(0xfe, rng.gen_range(1, 16));
+        cpu.bus.mem_write(0xfe, rng.gen_range(1, 16));
 
         if read_screen_state(cpu, &mut screen_state) {
             texture.update(None, &screen_state, 32 * 3).unwrap();
@@ -158,7 +149,7 @@ fn handle_user_input<T: BusOP>(cpu: &mut CPU<T>, event_pump: &mut EventPump) {
                 keycode: Some(Keycode::Up),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x77);
+                cpu.bus.mem_write(0xff, 0x77);
             }
 
             Event::KeyDown {
@@ -169,7 +160,7 @@ fn handle_user_input<T: BusOP>(cpu: &mut CPU<T>, event_pump: &mut EventPump) {
                 keycode: Some(Keycode::Down),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x73);
+                cpu.bus.mem_write(0xff, 0x73);
             }
 
             Event::KeyDown {
@@ -180,7 +171,7 @@ fn handle_user_input<T: BusOP>(cpu: &mut CPU<T>, event_pump: &mut EventPump) {
                 keycode: Some(Keycode::Left),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x61);
+                cpu.bus.mem_write(0xff, 0x61);
             }
 
             Event::KeyDown {
@@ -191,7 +182,7 @@ fn handle_user_input<T: BusOP>(cpu: &mut CPU<T>, event_pump: &mut EventPump) {
                 keycode: Some(Keycode::Right),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x64);
+                cpu.bus.mem_write(0xff, 0x64);
             }
 
             _ => { /* do nothing */ }
@@ -219,7 +210,7 @@ fn read_screen_state<T: BusOP>(mut cpu: CPU<T>, frame: &mut [u8; 32 * 3 * 32]) -
     let mut frame_idx = 0;
     let mut update = false;
     for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
+        let color_idx = cpu.bus.mem_read(i as u16);
         let (b1, b2, b3) = color(color_idx).rgb();
         if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
             frame[frame_idx] = b1;
@@ -232,11 +223,11 @@ fn read_screen_state<T: BusOP>(mut cpu: CPU<T>, frame: &mut [u8; 32 * 3 * 32]) -
     update
 }
 
-fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
+fn show_tile(chr_rom: &[u8], bank: usize, tile_n: usize) -> Frame {
     assert!(bank <= 1);
 
     let mut frame = Frame::new();
-    let bank = (bank * 0x1000) as usize;
+    let bank = bank * 0x1000;
 
     let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
     for y in 0..=7 {
@@ -245,8 +236,8 @@ fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
 
         for x in (0..=7).rev() {
             let value = (1 & upper) << 1 | (1 & lower);
-            upper = upper >> 1;
-            lower = lower >> 1;
+            upper >>= 1;
+            lower >>= 1;
             let rgb = match value {
                 0 => palette::SYSTEM_PALETTE[0x01],
                 1 => palette::SYSTEM_PALETTE[0x23],
@@ -0,0 +1,571 @@
+use derive_more::Display;
+
+use crate::cartridge::Rom;
+use crate::headless::Headless;
+use crate::joypad::JoypadButton;
+use crate::region::Region;
+use crate::render::frame::Frame;
+
+// Input movies in FCEUX's FM2 text format:
+//
+//   version 3
+//   romFilename pacman
+//   romChecksum base64:8+m6qwW0lSKaZ4VCKjNkWg==
+//   port0 1
+//   ...
+//   |0|........|||
+//   |0|...T....|||
+//
+// Header lines are `key value`, then one `|commands|port0|port1|port2|` line
+// per frame from power-on. A gamepad field is RLDUTSBA with anything but
+// '.' or ' ' pressed. Movies recorded here also carry a `frameHashes` header
+// with the hash of every frame, so playback can tell where it desynced.
+// Other emulators skip keys they don't know.
+
+const FM2_VERSION: &str = "3";
+const GAMEPAD_KEYS: &[u8; 8] = b"RLDUTSBA";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Port types from the header
+const SI_NONE: &str = "0";
+const SI_GAMEPAD: &str = "1";
+
+#[derive(Debug, Display, PartialEq)]
+pub enum MovieError {
+    #[display("line {_0}: {_1}")]
+    Parse(usize, String),
+
+    #[display("Movie was recorded on ROM {expected}, this one is {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[display("Movie uses {_0}, which isn't supported")]
+    Unsupported(String),
+
+    #[display("Movie has no {_0} header")]
+    MissingHeader(&'static str),
+
+    #[display("{_0}")]
+    Emulation(String),
+}
+
+impl std::error::Error for MovieError {}
+
+// FM2 command bits, only the two resets mean anything without FDS and VS
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MovieCommands(pub u8);
+
+impl MovieCommands {
+    pub const SOFT_RESET: u8 = 0b0000_0001;
+    pub const HARD_RESET: u8 = 0b0000_0010;
+
+    pub fn soft_reset(self) -> bool {
+        self.0 & MovieCommands::SOFT_RESET != 0
+    }
+
+    pub fn hard_reset(self) -> bool {
+        self.0 & MovieCommands::HARD_RESET != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    pub commands: MovieCommands,
+    pub port0: JoypadButton,
+    pub port1: JoypadButton,
+}
+
+impl MovieFrame {
+    pub fn new(port0: JoypadButton, port1: JoypadButton) -> Self {
+        MovieFrame {
+            commands: MovieCommands::default(),
+            port0,
+            port1,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Movie {
+    pub rom_filename: String,
+    // MD5 of PRG ROM followed by CHR ROM, the same digest FCEUX uses
+    pub rom_checksum: [u8; 16],
+    pub guid: String,
+    pub rerecord_count: u32,
+    pub comments: Vec<String>,
+    pub port1_connected: bool,
+    // FM2 only tells PAL from NTSC, Dendy movies say NTSC
+    pub pal: bool,
+    pub frames: Vec<MovieFrame>,
+    // Empty for movies made elsewhere
+    pub frame_hashes: Vec<u64>,
+}
+
+// Where playback first rendered something else than the recording
+#[derive(Debug, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+pub fn rom_checksum(rom: &Rom) -> [u8; 16] {
+    let mut context = md5::Context::new();
+    context.consume(&rom.prg_rom);
+    context.consume(&rom.chr_rom);
+    context.compute().0
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom: &Rom) -> Self {
+        let guid: [u8; 16] = rand::random();
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum: rom_checksum(rom),
+            guid: format_guid(&guid),
+            rerecord_count: 0,
+            comments: Vec::new(),
+            port1_connected: false,
+            pal: Region::from_timing(rom.header.timing) == Region::Pal,
+            frames: Vec::new(),
+            frame_hashes: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, input: MovieFrame, frame: &Frame) {
+        self.frames.push(input);
+        self.frame_hashes.push(frame.hash());
+    }
+
+    pub fn check_rom(&self, rom: &Rom) -> Result<(), MovieError> {
+        let actual = rom_checksum(rom);
+        if actual != self.rom_checksum {
+            return Err(MovieError::ChecksumMismatch {
+                expected: encode_checksum(&self.rom_checksum),
+                actual: encode_checksum(&actual),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn parse_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie {
+            rom_filename: String::new(),
+            rom_checksum: [0; 16],
+            guid: String::new(),
+            rerecord_count: 0,
+            comments: Vec::new(),
+            port1_connected: false,
+            pal: false,
+            frames: Vec::new(),
+            frame_hashes: Vec::new(),
+        };
+        let mut version = None;
+        let mut checksum = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim_end_matches('\r');
+            let error = |message: String| MovieError::Parse(number, message);
+
+            if let Some(record) = line.strip_prefix('|') {
+                movie
+                    .frames
+                    .push(parse_frame(record, movie.port1_connected).map_err(error)?);
+                continue;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" => version = Some(value.to_string()),
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    checksum = Some(
+                        decode_checksum(value)
+                            .ok_or_else(|| error(format!("bad ROM checksum {:?}", value)))?,
+                    )
+                }
+                "guid" => movie.guid = value.to_string(),
+                "rerecordCount" => {
+                    movie.rerecord_count = value
+                        .parse()
+                        .map_err(|_| error(format!("bad rerecord count {:?}", value)))?
+                }
+                "comment" => movie.comments.push(value.to_string()),
+                "port0" if value != SI_GAMEPAD => {
+                    return Err(MovieError::Unsupported(format!(
+                        "input device {} on port 0",
+                        value
+                    )));
+                }
+                "port1" => match value {
+                    SI_NONE => movie.port1_connected = false,
+                    SI_GAMEPAD => movie.port1_connected = true,
+                    _ => {
+                        return Err(MovieError::Unsupported(format!(
+                            "input device {} on port 1",
+                            value
+                        )));
+                    }
+                },
+                "port2" if value != SI_NONE => {
+                    return Err(MovieError::Unsupported(
+                        "a Famicom expansion device".to_string(),
+                    ));
+                }
+                "fourscore" if value != "0" => {
+                    return Err(MovieError::Unsupported("the Four Score".to_string()));
+                }
+                "palFlag" => movie.pal = value != "0",
+                "FDS" if value != "0" => {
+                    return Err(MovieError::Unsupported(
+                        "the Famicom Disk System".to_string(),
+                    ));
+                }
+                "savestate" => {
+                    return Err(MovieError::Unsupported(
+                        "a start from a save state".to_string(),
+                    ));
+                }
+                "frameHashes" => {
+                    movie.frame_hashes = value
+                        .split_whitespace()
+                        .map(|hash| u64::from_str_radix(hash, 16))
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| error("bad frame hash".to_string()))?
+                }
+                _ => {}
+            }
+        }
+
+        if version.as_deref() != Some(FM2_VERSION) {
+            return Err(MovieError::Unsupported(format!(
+                "FM2 version {:?}",
+                version.unwrap_or_default()
+            )));
+        }
+        movie.rom_checksum = checksum.ok_or(MovieError::MissingHeader("romChecksum"))?;
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut out = String::new();
+        out += &format!("version {}\n", FM2_VERSION);
+        out += "emuVersion 0\n";
+        out += &format!("rerecordCount {}\n", self.rerecord_count);
+        out += &format!("palFlag {}\n", self.pal as u8);
+        out += &format!("romFilename {}\n", self.rom_filename);
+        out += &format!("romChecksum {}\n", encode_checksum(&self.rom_checksum));
+        out += &format!("guid {}\n", self.guid);
+        out += "fourscore 0\n";
+        out += "microphone 0\n";
+        out += &format!("port0 {}\n", SI_GAMEPAD);
+        out += &format!(
+            "port1 {}\n",
+            if self.port1_connected {
+                SI_GAMEPAD
+            } else {
+                SI_NONE
+            }
+        );
+        out += &format!("port2 {}\n", SI_NONE);
+        out += "FDS 0\n";
+        out += "NewPPU 0\n";
+        for comment in &self.comments {
+            out += &format!("comment {}\n", comment);
+        }
+        if !self.frame_hashes.is_empty() {
+            let hashes: Vec<String> = self
+                .frame_hashes
+                .iter()
+                .map(|hash| format!("{:016x}", hash))
+                .collect();
+            out += &format!("frameHashes {}\n", hashes.join(" "));
+        }
+
+        for frame in &self.frames {
+            let port1 = if self.port1_connected {
+                format_gamepad(frame.port1)
+            } else {
+                String::new()
+            };
+            out += &format!(
+                "|{}|{}|{}||\n",
+                frame.commands.0,
+                format_gamepad(frame.port0),
+                port1
+            );
+        }
+        out
+    }
+}
+
+/// Plays `movie` back from power-on. `on_frame` sees every frame and the
+/// first frame whose hash differs from the recording is returned.
+pub fn play_movie<F>(mut rom: Rom, movie: &Movie, mut on_frame: F) -> Result<Option<Desync>, MovieError>
+where
+    F: FnMut(usize, &Frame),
+{
+    movie.check_rom(&rom)?;
+    // The movie's region wins over the ROM's
+    let region = Region::from_timing(rom.header.timing);
+    if movie.pal {
+        rom.header.timing = Region::Pal.timing();
+    } else if region == Region::Pal {
+        rom.header.timing = Region::Ntsc.timing();
+    }
+
+    let mut console = Headless::new(rom.clone()).map_err(MovieError::Emulation)?;
+    let mut desync = None;
+    for (number, input) in movie.frames.iter().enumerate() {
+        if input.commands.hard_reset() {
+            console = Headless::new(rom.clone()).map_err(MovieError::Emulation)?;
+        } else if input.commands.soft_reset() {
+            console.reset();
+        }
+
+        let frame = console
+            .run_frame(input.port0, input.port1)
+            .map_err(MovieError::Emulation)?;
+        if let Some(&expected) = movie.frame_hashes.get(number)
+            && desync.is_none()
+            && frame.hash() != expected
+        {
+            desync = Some(Desync {
+                frame: number,
+                expected,
+                actual: frame.hash(),
+            });
+        }
+        on_frame(number, frame);
+    }
+    Ok(desync)
+}
+
+fn parse_frame(record: &str, port1_connected: bool) -> Result<MovieFrame, String> {
+    let fields: Vec<&str> = record.split('|').collect();
+    if fields.len() < 3 {
+        return Err(format!(
+            "expected |commands|port0|port1|port2|, got {:?}",
+            record
+        ));
+    }
+    let commands = fields[0]
+        .trim()
+        .parse()
+        .map_err(|_| format!("bad commands {:?}", fields[0]))?;
+    let port1 = if port1_connected {
+        parse_gamepad(fields[2])?
+    } else {
+        JoypadButton::empty()
+    };
+
+    Ok(MovieFrame {
+        commands: MovieCommands(commands),
+        port0: parse_gamepad(fields[1])?,
+        port1,
+    })
+}
+
+fn parse_gamepad(field: &str) -> Result<JoypadButton, String> {
+    if field.len() != GAMEPAD_KEYS.len() {
+        return Err(format!("expected 8 gamepad keys, got {:?}", field));
+    }
+    // RIGHT is the top bit and comes first
+    let bits = field.bytes().fold(0u8, |bits, key| {
+        bits << 1 | (key != b'.' && key != b' ') as u8
+    });
+    Ok(JoypadButton::from_bits_truncate(bits))
+}
+
+fn format_gamepad(buttons: JoypadButton) -> String {
+    GAMEPAD_KEYS
+        .iter()
+        .enumerate()
+        .map(|(i, &key)| {
+            if buttons.bits() & (0x80 >> i) != 0 {
+                key as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn format_guid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn encode_checksum(checksum: &[u8; 16]) -> String {
+    let mut out = String::from("base64:");
+    for chunk in checksum.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// FCEUX writes base64, older movies have plain hex
+fn decode_checksum(value: &str) -> Option<[u8; 16]> {
+    let mut checksum = [0; 16];
+    if let Some(encoded) = value.strip_prefix("base64:") {
+        let mut bytes = Vec::new();
+        let digits: Vec<u32> = encoded
+            .trim_end_matches('=')
+            .bytes()
+            .map(|c| BASE64.iter().position(|&d| d == c).map(|d| d as u32))
+            .collect::<Option<_>>()?;
+        for chunk in digits.chunks(4) {
+            let bits = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |bits, (i, &d)| bits | d << (18 - 6 * i));
+            bytes.extend((0..chunk.len().saturating_sub(1)).map(|i| (bits >> (16 - 8 * i)) as u8));
+        }
+        if bytes.len() != 16 {
+            return None;
+        }
+        checksum.copy_from_slice(&bytes);
+    } else {
+        let hex = value.trim_start_matches("0x");
+        if hex.len() != 32 {
+            return None;
+        }
+        for (i, byte) in checksum.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+    }
+    Some(checksum)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pacman() -> Rom {
+        Rom::new(&std::fs::read("roms/pacman.nes").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn checksums_round_trip() {
+        let checksum: [u8; 16] = *b"0123456789abcdef";
+        let encoded = encode_checksum(&checksum);
+        assert_eq!(encoded, "base64:MDEyMzQ1Njc4OWFiY2RlZg==");
+        assert_eq!(decode_checksum(&encoded), Some(checksum));
+        assert_eq!(
+            decode_checksum("0x30313233343536373839616263646566"),
+            Some(checksum)
+        );
+        assert_eq!(decode_checksum("base64:MDEy"), None);
+    }
+
+    #[test]
+    fn parses_fceux_movies() {
+        let text = "version 3\nemuVersion 22020\nrerecordCount 7\npalFlag 0\nromFilename pacman\n\
+                    romChecksum base64:MDEyMzQ1Njc4OWFiY2RlZg==\nguid 1\nfourscore 0\nmicrophone 0\n\
+                    port0 1\nport1 0\nport2 0\nFDS 0\nNewPPU 0\ncomment author someone\n\
+                    |2|........|||\n|0|R..UT..A|||\n|1|  D   B |||\n";
+        let movie = Movie::parse_fm2(text).unwrap();
+
+        assert_eq!(movie.rerecord_count, 7);
+        assert_eq!(movie.comments, vec!["author someone"]);
+        assert_eq!(movie.rom_checksum, *b"0123456789abcdef");
+        assert!(movie.frames[0].commands.hard_reset());
+        assert!(movie.frames[2].commands.soft_reset());
+        assert_eq!(
+            movie.frames[1].port0,
+            JoypadButton::RIGHT | JoypadButton::UP | JoypadButton::START | JoypadButton::A
+        );
+        assert_eq!(movie.frames[2].port0, JoypadButton::DOWN | JoypadButton::B);
+        assert!(movie.frame_hashes.is_empty());
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_played() {
+        let header = "version 3\nromChecksum base64:MDEyMzQ1Njc4OWFiY2RlZg==\nport0 1\n";
+        assert_eq!(
+            Movie::parse_fm2(&format!("{}savestate 0123\n", header)),
+            Err(MovieError::Unsupported(
+                "a start from a save state".to_string()
+            ))
+        );
+        assert_eq!(
+            Movie::parse_fm2(&format!("{}|0|...|||\n", header)),
+            Err(MovieError::Parse(
+                4,
+                "expected 8 gamepad keys, got \"...\"".to_string()
+            ))
+        );
+        assert!(
+            Movie::parse_fm2("version 2\nromChecksum base64:MDEyMzQ1Njc4OWFiY2RlZg==\n").is_err()
+        );
+    }
+
+    #[test]
+    fn recordings_round_trip_and_play_back() {
+        let mut movie = Movie::new("pacman", &pacman());
+        movie.comments.push("title screen".to_string());
+
+        let mut console = Headless::new(pacman()).unwrap();
+        for number in 0..10 {
+            let buttons = if number == 5 {
+                JoypadButton::START
+            } else {
+                JoypadButton::empty()
+            };
+            let input = MovieFrame::new(buttons, JoypadButton::empty());
+            movie.record(input, console.run_frame(input.port0, input.port1).unwrap());
+        }
+
+        let movie = Movie::parse_fm2(&movie.to_fm2()).unwrap();
+        assert_eq!(movie.frames[5].port0, JoypadButton::START);
+        assert_eq!(movie.frame_hashes.len(), 10);
+        assert_eq!(play_movie(pacman(), &movie, |_, _| {}), Ok(None));
+    }
+
+    #[test]
+    fn pal_flag_follows_the_rom() {
+        assert!(!Movie::new("pacman", &pacman()).pal);
+
+        let mut rom = pacman();
+        rom.header.timing = Region::Pal.timing();
+        let movie = Movie::new("pacman", &rom);
+        assert!(movie.to_fm2().contains("palFlag 1\n"));
+        assert!(Movie::parse_fm2(&movie.to_fm2()).unwrap().pal);
+    }
+
+    #[test]
+    fn reports_desyncs_and_wrong_roms() {
+        let mut movie = Movie::new("pacman", &pacman());
+        movie.frames = vec![MovieFrame::new(JoypadButton::empty(), JoypadButton::empty()); 4];
+        movie.frame_hashes = vec![0; 4];
+
+        let desync = play_movie(pacman(), &movie, |_, _| {}).unwrap().unwrap();
+        assert_eq!(desync.frame, 0);
+        assert_eq!(desync.expected, 0);
+
+        let nestest = Rom::new(&std::fs::read("roms/nestest.nes").unwrap()).unwrap();
+        assert!(matches!(
+            play_movie(nestest, &movie, |_, _| {}),
+            Err(MovieError::ChecksumMismatch { .. })
+        ));
+    }
+}
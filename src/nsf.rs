@@ -0,0 +1,481 @@
+use bitflags::bitflags;
+
+use crate::apu::{Apu, NTSC_CPU_CLOCK};
+use crate::bus::BusOP;
+use crate::cartridge::header::Timing;
+use crate::region::Region;
+use crate::cpu::{CPU, Mem};
+use crate::logging::verbose;
+
+const NSF_TAG: &[u8] = b"NESM\x1A";
+pub const NSF_HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+
+// INIT and PLAY return here, it's never fetched from
+const RETURN_ADDR: u16 = 0x4100;
+// INIT runs once per track and shouldn't take anywhere near this long
+const INIT_CYCLE_LIMIT: usize = NTSC_CPU_CLOCK as usize;
+
+bitflags! {
+    // Byte $7B, sound chips on the cartridge besides the APU
+    pub struct ExpansionChips: u8 {
+        const VRC6       = 0b0000_0001;
+        const VRC7       = 0b0000_0010;
+        const FDS        = 0b0000_0100;
+        const MMC5       = 0b0000_1000;
+        const N163       = 0b0001_0000;
+        const SUNSOFT_5B = 0b0010_0000;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NsfHeader {
+    pub version: u8,
+    pub song_count: u8,
+    // 1 based, like the track numbers players show
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // Microseconds between PLAY calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // Banks for $8000-$FFFF in 4K steps, all zero when not bankswitched
+    pub bankswitch: [u8; 8],
+    // Ntsc, Pal or Multi
+    pub timing: Timing,
+    pub expansion: ExpansionChips,
+}
+
+// The header fields are zero padded, and sometimes not terminated at all
+fn header_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+fn read_u16(raw: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([raw[at], raw[at + 1]])
+}
+
+impl NsfHeader {
+    pub fn parse(raw: &[u8]) -> Result<NsfHeader, String> {
+        if !raw.starts_with(NSF_TAG) {
+            return Err("Wrong NSF Tag!".to_string());
+        }
+        if raw.len() < NSF_HEADER_SIZE {
+            return Err("NSF header is cut short!".to_string());
+        }
+
+        let header = NsfHeader {
+            version: raw[0x05],
+            song_count: raw[0x06],
+            starting_song: raw[0x07].max(1),
+            load_addr: read_u16(raw, 0x08),
+            init_addr: read_u16(raw, 0x0A),
+            play_addr: read_u16(raw, 0x0C),
+            title: header_string(&raw[0x0E..0x2E]),
+            artist: header_string(&raw[0x2E..0x4E]),
+            copyright: header_string(&raw[0x4E..0x6E]),
+            ntsc_speed: read_u16(raw, 0x6E),
+            bankswitch: raw[0x70..0x78].try_into().unwrap(),
+            pal_speed: read_u16(raw, 0x78),
+            timing: match raw[0x7A] & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                _ => Timing::Multi,
+            },
+            expansion: ExpansionChips::from_bits_truncate(raw[0x7B]),
+        };
+
+        if header.song_count == 0 {
+            return Err("NSF has no songs".to_string());
+        }
+        if header.load_addr < 0x8000 && !header.expansion.contains(ExpansionChips::FDS) {
+            return Err(format!("NSF load address {:04X} is below $8000", header.load_addr));
+        }
+        Ok(header)
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bankswitch.iter().any(|&bank| bank != 0)
+    }
+
+    // PAL only tunes get PAL timing, everything else NTSC
+    pub fn is_pal(&self) -> bool {
+        self.timing == Timing::Pal
+    }
+
+    pub fn play_speed(&self) -> u16 {
+        if self.is_pal() { self.pal_speed } else { self.ntsc_speed }
+    }
+
+    pub fn region(&self) -> Region {
+        if self.is_pal() { Region::Pal } else { Region::Ntsc }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Nsf {
+    pub header: NsfHeader,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, String> {
+        let header = NsfHeader::parse(raw)?;
+        if raw.len() == NSF_HEADER_SIZE {
+            return Err("NSF has no program data".to_string());
+        }
+        Ok(Nsf {
+            header,
+            data: raw[NSF_HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+// $0000-$07FF RAM, $4000-$4017 APU, $5FF8-$5FFF bank select, $6000-$7FFF
+// RAM and $8000-$FFFF ROM in eight 4K banks. Expansion audio isn't played
+pub struct NsfBus {
+    ram: [u8; 2048],
+    prg_ram: [u8; 8192],
+    rom: Vec<u8>,
+    banks: [usize; 8],
+    apu: Apu,
+    cycles: usize,
+}
+
+impl NsfBus {
+    pub fn new(nsf: &Nsf, apu: Apu) -> NsfBus {
+        let header = &nsf.header;
+        let (rom, banks) = if header.is_bankswitched() {
+            // Data starts at the load address' offset into its bank
+            let padding = (header.load_addr & 0x0FFF) as usize;
+            let mut rom = vec![0; padding];
+            rom.extend(&nsf.data);
+            rom.resize(rom.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+            (rom, header.bankswitch.map(|bank| bank as usize))
+        } else {
+            let mut rom = vec![0; 8 * BANK_SIZE];
+            let start = header.load_addr.saturating_sub(0x8000) as usize;
+            let len = nsf.data.len().min(rom.len() - start);
+            rom[start..start + len].copy_from_slice(&nsf.data[..len]);
+            (rom, [0, 1, 2, 3, 4, 5, 6, 7])
+        };
+
+        NsfBus {
+            ram: [0; 2048],
+            prg_ram: [0; 8192],
+            rom,
+            banks,
+            apu,
+            cycles: 0,
+        }
+    }
+
+    pub fn apu(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let slot = (addr as usize - 0x8000) / BANK_SIZE;
+        let bank = self.banks[slot] % (self.rom.len() / BANK_SIZE);
+        self.rom[bank * BANK_SIZE + addr as usize % BANK_SIZE]
+    }
+}
+
+impl BusOP for NsfBus {
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+        self.apu.tick(cycles);
+    }
+
+    fn poll_nmi_status(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn cycles(&mut self) -> usize {
+        self.cycles
+    }
+
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4015 => self.apu.peek_status(),
+            _ => self.mem_read(addr),
+        }
+    }
+}
+
+impl Mem for NsfBus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram[(addr & 0x07FF) as usize],
+            0x4015 => self.apu.read_status(),
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => self.read_rom(addr),
+            _ => {
+                verbose!(target: "bus", "Reading unmapped NSF address {:04X}", addr);
+                0
+            }
+        }
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram[(addr & 0x07FF) as usize] = data,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, data),
+            0x5FF8..=0x5FFF => self.banks[(addr - 0x5FF8) as usize] = data as usize,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => {
+                verbose!(target: "bus", "Ignoring NSF write {:04X} = {:02X}", addr, data);
+            }
+        }
+    }
+}
+
+// The driver a real NSF player cartridge would have: INIT once for the
+// chosen track, then PLAY every `play_speed` microseconds. Between calls the
+// CPU idles while the APU keeps running
+pub struct NsfPlayer {
+    nsf: Nsf,
+    sample_rate: u32,
+    cpu: CPU<NsfBus>,
+    play_period: usize,
+    next_play: usize,
+}
+
+fn new_apu(region: Region, sample_rate: u32) -> Apu {
+    let mut apu = Apu::with_sample_rate(sample_rate);
+    apu.set_region(region);
+    apu
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, sample_rate: u32) -> Result<NsfPlayer, String> {
+        // Only the FDS has RAM down there to load into
+        if nsf.header.load_addr < 0x8000 {
+            return Err(format!("FDS tunes loading at {:04X} are not supported", nsf.header.load_addr));
+        }
+        let region = nsf.header.region();
+        let bus = NsfBus::new(&nsf, new_apu(region, sample_rate));
+        // A speed of 0 is broken, use the usual frame rate
+        let play_period = match (nsf.header.play_speed(), region) {
+            (0, Region::Pal) => (region.cpu_clock() / 50.0) as usize,
+            (0, _) => (region.cpu_clock() / 60.0) as usize,
+            (speed, _) => (speed as f64 * region.cpu_clock() / 1_000_000.0) as usize,
+        };
+        Ok(NsfPlayer {
+            nsf,
+            sample_rate,
+            cpu: CPU::new(bus),
+            play_period,
+            next_play: 0,
+        })
+    }
+
+    pub fn header(&self) -> &NsfHeader {
+        &self.nsf.header
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Starts `track` (1 based) from a clean console
+    pub fn start_track(&mut self, track: u8) -> Result<(), String> {
+        let header = &self.nsf.header;
+        if track == 0 || track > header.song_count {
+            return Err(format!("Track {} is not in 1-{}", track, header.song_count));
+        }
+        let (init_addr, bankswitch) = (header.init_addr, header.bankswitch);
+        let pal = header.is_pal();
+
+        self.cpu = CPU::new(NsfBus::new(&self.nsf, new_apu(header.region(), self.sample_rate)));
+        let bus = &mut self.cpu.bus;
+        for addr in 0x4000..=0x4013 {
+            bus.mem_write(addr, 0);
+        }
+        bus.mem_write(0x4015, 0x0F);
+        bus.mem_write(0x4017, 0x40);
+        if self.nsf.header.is_bankswitched() {
+            for (slot, bank) in bankswitch.into_iter().enumerate() {
+                bus.mem_write(0x5FF8 + slot as u16, bank);
+            }
+        }
+
+        self.cpu.register_a = track - 1;
+        self.cpu.register_x = pal as u8;
+        self.call(init_addr);
+        while self.cpu.program_counter != RETURN_ADDR {
+            if self.cpu.bus.cycles() > INIT_CYCLE_LIMIT {
+                return Err(format!("INIT at {:04X} didn't return", init_addr));
+            }
+            self.cpu.step(|_| {}).map_err(|err| err.to_string())?;
+        }
+        // INIT's own sound is dropped, the track starts with the first PLAY
+        self.cpu.bus.apu().take_samples();
+        self.next_play = self.cpu.bus.cycles();
+        Ok(())
+    }
+
+    // Like a JSR from RETURN_ADDR - 1
+    fn call(&mut self, addr: u16) {
+        self.cpu.push_stack_u16(RETURN_ADDR - 1);
+        self.cpu.program_counter = addr;
+    }
+
+    // Plays on for `seconds` and returns the samples
+    pub fn render(&mut self, seconds: f64) -> Result<Vec<f32>, String> {
+        let clock = self.nsf.header.region().cpu_clock();
+        let end = self.cpu.bus.cycles() + (seconds * clock) as usize;
+        let play_addr = self.nsf.header.play_addr;
+
+        while self.cpu.bus.cycles() < end {
+            if self.cpu.program_counter != RETURN_ADDR {
+                self.cpu.step(|_| {}).map_err(|err| err.to_string())?;
+            } else if self.cpu.bus.cycles() >= self.next_play {
+                // A PLAY that overruns its period delays the next one
+                self.next_play += self.play_period;
+                self.call(play_addr);
+            } else {
+                self.cpu.bus.tick(1);
+            }
+        }
+        Ok(self.cpu.bus.apu().take_samples())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nsf_file(load: u16, init: u16, play: u16, bankswitch: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.extend([1, 3, 2]);
+        raw.extend(load.to_le_bytes());
+        raw.extend(init.to_le_bytes());
+        raw.extend(play.to_le_bytes());
+        raw.resize(0x0E, 0);
+        raw.extend(b"Test Tune\0");
+        raw.resize(0x2E, 0);
+        raw.extend(b"Somebody");
+        raw.resize(0x6E, 0);
+        raw.extend(16639u16.to_le_bytes());
+        raw.extend(bankswitch);
+        raw.extend(19997u16.to_le_bytes());
+        raw.extend([0b10, 0b0000_0101, 0, 0, 0, 0]);
+        raw.extend(data);
+        raw
+    }
+
+    // INIT starts a 440 Hz square on pulse 1, PLAY counts its calls in $00
+    fn tone_nsf() -> Nsf {
+        let mut code = vec![
+            0x85, 0x01, // STA $01, the track number
+            0xA9, 0x01, 0x8D, 0x15, 0x40, // LDA #$01, STA $4015
+            0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+            0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD, STA $4002
+            0xA9, 0xF8, 0x8D, 0x03, 0x40, // LDA #$F8, STA $4003
+            0x60, // RTS
+        ];
+        code.resize(0x20, 0xEA);
+        code.extend([0xE6, 0x00, 0x60]); // INC $00, RTS
+        Nsf::new(&nsf_file(0x8000, 0x8000, 0x8020, [0; 8], &code)).unwrap()
+    }
+
+    #[test]
+    fn parses_headers() {
+        let header = tone_nsf().header;
+        assert_eq!(header.song_count, 3);
+        assert_eq!(header.starting_song, 2);
+        assert_eq!(header.init_addr, 0x8000);
+        assert_eq!(header.play_addr, 0x8020);
+        assert_eq!(header.title, "Test Tune");
+        assert_eq!(header.artist, "Somebody");
+        assert_eq!(header.copyright, "");
+        assert_eq!(header.ntsc_speed, 16639);
+        assert_eq!(header.pal_speed, 19997);
+        assert_eq!(header.timing, Timing::Multi);
+        assert_eq!(header.expansion, ExpansionChips::VRC6 | ExpansionChips::FDS);
+        assert!(!header.is_bankswitched());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(NsfHeader::parse(b"NES\x1A").unwrap_err(), "Wrong NSF Tag!");
+        assert_eq!(NsfHeader::parse(b"NESM\x1A\x01").unwrap_err(), "NSF header is cut short!");
+
+        let empty = nsf_file(0x8000, 0x8000, 0x8000, [0, 1, 0, 0, 0, 0, 0, 0], &[]);
+        assert_eq!(Nsf::new(&empty).unwrap_err(), "NSF has no program data");
+        let nsf = Nsf {
+            header: NsfHeader::parse(&empty).unwrap(),
+            data: Vec::new(),
+        };
+        assert_eq!(NsfBus::new(&nsf, Apu::new()).mem_read(0x9000), 0);
+
+        let mut raw = nsf_file(0x6000, 0x6000, 0x6000, [0; 8], &[0x60]);
+        raw[0x7B] = 0;
+        assert_eq!(
+            NsfHeader::parse(&raw).unwrap_err(),
+            "NSF load address 6000 is below $8000"
+        );
+
+        raw[0x7B] = ExpansionChips::FDS.bits();
+        let nsf = Nsf::new(&raw).unwrap();
+        assert_eq!(
+            NsfPlayer::new(nsf, 44100).err().unwrap(),
+            "FDS tunes loading at 6000 are not supported"
+        );
+    }
+
+    #[test]
+    fn bankswitches_in_4k_steps() {
+        // Loaded $100 into the bank, three banks of data
+        let data: Vec<u8> = (0..3).flat_map(|bank| vec![bank; BANK_SIZE]).collect();
+        let nsf = Nsf::new(&nsf_file(0x8100, 0x8100, 0x8100, [0, 1, 2, 0, 0, 0, 0, 2], &data)).unwrap();
+        let mut bus = NsfBus::new(&nsf, Apu::new());
+
+        assert_eq!(bus.mem_read(0x80FF), 0);
+        assert_eq!(bus.mem_read(0x8100), 0);
+        assert_eq!(bus.mem_read(0x9100), 1);
+        assert_eq!(bus.mem_read(0xF100), 2);
+        bus.mem_write(0x5FF8, 3);
+        assert_eq!(bus.mem_read(0x80FF), 2);
+    }
+
+    #[test]
+    fn calls_init_then_play_at_the_header_rate() {
+        let mut player = NsfPlayer::new(tone_nsf(), 44100).unwrap();
+        assert!(player.start_track(0).is_err());
+        assert!(player.start_track(4).is_err());
+
+        player.start_track(3).unwrap();
+        assert_eq!(player.cpu.bus.mem_read(0x01), 2);
+
+        let samples = player.render(1.0).unwrap();
+        // 16639us between calls is 60.1 Hz
+        assert_eq!(player.cpu.bus.mem_read(0x00), 61);
+        assert!((44090..=44110).contains(&samples.len()));
+        let crossings = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!((435..=445).contains(&crossings), "{} crossings", crossings);
+    }
+
+    #[test]
+    fn pal_tunes_run_at_pal_clocks() {
+        let mut raw = nsf_file(0x8000, 0x8000, 0x8000, [0; 8], &[0xE6, 0x00, 0x60]);
+        raw[0x7A] = 0b01;
+        let mut player = NsfPlayer::new(Nsf::new(&raw).unwrap(), 44100).unwrap();
+        player.start_track(1).unwrap();
+
+        let samples = player.render(1.0).unwrap();
+        // 19997us between calls is 50 Hz, plus the INIT call
+        assert_eq!(player.cpu.bus.mem_read(0x00), 52);
+        assert!((44090..=44110).contains(&samples.len()));
+        assert_eq!(player.cpu.bus.apu().region(), Region::Pal);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod registers;
 
 use crate::cartridge::Mirroring;
+use crate::logging::verbose;
+use crate::region::Region;
 
 use registers::address::PPUADDR;
 use registers::control::PPUCTRL;
@@ -12,8 +14,29 @@ use registers::w::WREG;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// Where the PPU currently is in the frame, as shown in trace logs
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PpuTiming {
+    pub scanline: u16,
+    pub dot: usize,
+    pub frame: u64,
+}
+
+// Palette RAM repeats every 32 bytes up to 0x3fff, and $3F10/$3F14/$3F18/$3F1C
+// are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = (addr & 0x1f) as usize;
+    if index & 0b1_0011 == 0b1_0000 {
+        index & 0x0f
+    } else {
+        index
+    }
+}
+
 pub struct NesPPU {
-    pub chr_rom: Vec<u8>,
+    // Pattern tables at $0000-$1FFF, ROM unless `chr_ram` is set
+    pub chr: Vec<u8>,
+    pub chr_ram: bool,
     pub palette_table: [u8; 32],
     pub vram: [u8; 7936],
     pub internal_data_buf: u8,
@@ -29,7 +52,9 @@ pub struct NesPPU {
 
     scanline: u16,
     cycles: usize,
+    frame: u64,
     pub nmi_interrupt: Option<u8>,
+    pub region: Region,
 
     // pub
     pub mirroring: Mirroring,
@@ -37,10 +62,19 @@ pub struct NesPPU {
 
 impl NesPPU {
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        NesPPU::with_chr(chr_rom, false, mirroring)
+    }
+
+    pub fn with_chr_ram(size: usize, mirroring: Mirroring) -> Self {
+        NesPPU::with_chr(vec![0; size], true, mirroring)
+    }
+
+    fn with_chr(chr: Vec<u8>, chr_ram: bool, mirroring: Mirroring) -> Self {
         let latch = Rc::new(RefCell::new(WREG::new()));
         NesPPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            chr,
+            chr_ram,
+            mirroring,
             vram: [0; 7936],
             ctrl: PPUCTRL::new(),
             mask: PPUMASK::new(),
@@ -53,7 +87,9 @@ impl NesPPU {
 
             scanline: 0,
             cycles: 0,
+            frame: 0,
             nmi_interrupt: None,
+            region: Region::Ntsc,
 
             internal_data_buf: 0,
             palette_table: [0; 32],
@@ -63,24 +99,33 @@ impl NesPPU {
     pub fn tick(&mut self, cycles: u8) -> bool {
         self.cycles += cycles as usize;
         if self.cycles >= 341 {
-            self.cycles = self.cycles - 341;
+            self.cycles -= 341;
             self.scanline += 1;
-            if self.scanline == 241 {
+            if self.scanline == self.region.vblank_scanline() {
+                self.status.set_vblank_status(true);
                 if self.ctrl.generate_vblank_nmi() {
-                    self.status.set_vblank_status(true);
-                    todo!("Should trigger NMI interrupt")
+                    self.nmi_interrupt = Some(1);
                 }
             }
         }
 
-        if self.scanline >= 252 {
+        if self.scanline >= self.region.scanlines() {
             self.scanline = 0;
+            self.frame += 1;
             self.nmi_interrupt = None;
             self.status.reset_vblank_status();
             return true;
         }
 
-        return false;
+        false
+    }
+
+    pub fn timing(&self) -> PpuTiming {
+        PpuTiming {
+            scanline: self.scanline,
+            dot: self.cycles,
+            frame: self.frame,
+        }
     }
 
     pub fn direct_write_to_ppu_addr(&mut self, value: u8) {
@@ -128,12 +173,12 @@ impl NesPPU {
     }
 
     pub fn direct_read_oam_data(&mut self) -> u8 {
-        println!("DReading oam_data @ 0x{:02X}", 0);
+        verbose!(target: "ppu", "DReading oam_data @ 0x{:02X}", 0);
         self.oam_data[0]
     }
 
     pub fn read_oam_data(&mut self) -> u8 {
-        println!("Reading oam_data @ 0x{:02X}", self.oam_addr);
+        verbose!(target: "ppu", "Reading oam_data @ 0x{:02X}", self.oam_addr);
         self.oam_data[self.oam_addr as usize]
     }
 
@@ -158,8 +203,7 @@ impl NesPPU {
     }
 
     pub fn direct_read_status(&mut self) -> u8 {
-        let data = self.status.bits();
-        data
+        self.status.bits()
     }
 
     pub fn read_status(&mut self) -> u8 {
@@ -176,7 +220,7 @@ impl NesPPU {
     }
 
     pub fn direct_write_to_oam_data(&mut self, value: u8) {
-        println!("DWriting oam_data @ 0x{:02X}", 0);
+        verbose!(target: "ppu", "DWriting oam_data @ 0x{:02X}", 0);
         self.oam_data[0] = value;
     }
 
@@ -192,14 +236,16 @@ impl NesPPU {
     //   [ A ] [ B ]
     //   [ a ] [ b ]
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
-        println!("vram_addr: {}", addr);
-        let mirrored_vram = addr & 0b0011_1111_1111_1111; // mirror down 0x3000-0x3eff to 0x2000-0x2eff
-        println!("mirrored_addr: {}", addr);
-        dbg!(&self.mirroring);
-        let vram_index = mirrored_vram - 0x200; // to vram vector
-        dbg!(vram_index);
+        let mirrored_vram = addr & 0b0010_1111_1111_1111; // mirror down 0x3000-0x3eff to 0x2000-0x2eff
+        let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400; // to the name table index
-        dbg!(name_table);
+        verbose!(
+            target: "ppu",
+            "vram_addr: {:04X}, name table {} with {:?} mirroring",
+            addr,
+            name_table,
+            self.mirroring
+        );
         match (&self.mirroring, name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
@@ -209,16 +255,43 @@ impl NesPPU {
         }
     }
 
+    // Boards with less than 8K of CHR repeat it
+    fn read_chr(&self, addr: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, addr: u16, data: u8) {
+        if !self.chr_ram || self.chr.is_empty() {
+            verbose!(target: "ppu", "Ignoring write to CHR ROM: {:04X} = {:02X}", addr, data);
+            return;
+        }
+        let len = self.chr.len();
+        self.chr[addr as usize % len] = data;
+    }
+
     pub fn direct_read_data(&mut self) -> u8 {
         let addr = self.addr.get();
 
         match addr {
-            0..=0x1fff => self.chr_rom[addr as usize],
+            0..=0x1fff => self.read_chr(addr),
             // 0x2000..=0x2fff => {
             0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
-            // 0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used"),
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            // The address register never goes past 0x3fff
+            _ => self.palette_table[palette_index(addr)],
+        }
+    }
+
+    // What read_data would return, without moving the address or
+    // refilling the read buffer
+    pub fn peek_data(&self) -> u8 {
+        let addr = self.addr.get();
+
+        match addr {
+            0..=0x3eff => self.internal_data_buf,
+            _ => self.palette_table[palette_index(addr)],
         }
     }
 
@@ -229,7 +302,7 @@ impl NesPPU {
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.read_chr(addr);
                 result
             }
             // 0x2000..=0x2fff => {
@@ -238,9 +311,7 @@ impl NesPPU {
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             }
-            // 0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used"),
-            0x3f00..=0x3fff => self.palette_table[(addr - 0x3f00) as usize],
-            _ => panic!("unexpected access to mirrored space {}", addr),
+            _ => self.palette_table[palette_index(addr)],
         }
     }
 
@@ -253,23 +324,16 @@ impl NesPPU {
 
         match addr {
             0..=0x1fff => {
-                self.chr_rom[addr as usize] = data;
+                self.write_chr(addr, data);
                 self.internal_data_buf = data;
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = data;
                 self.internal_data_buf = data;
             }
-            0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used"),
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = data;
+            _ => {
+                self.palette_table[palette_index(addr)] = data;
             }
-            0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = data;
-            }
-            _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
@@ -281,24 +345,58 @@ impl NesPPU {
 
         match addr {
             0..=0x1fff => {
-                self.chr_rom[addr as usize] = data;
+                self.write_chr(addr, data);
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = data;
             }
-            0x3000..=0x3eff => panic!("addr space 0x3000..0x3eff is not expected to be used"),
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = addr - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = data;
-            }
-            0x3f00..=0x3fff => {
-                self.palette_table[(addr - 0x3f00) as usize] = data;
+            _ => {
+                self.palette_table[palette_index(addr)] = data;
             }
-            _ => panic!("unexpected access to mirrored space {}", addr),
         }
         if addr == 0x2007 {
             self.addr.increment(1);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_chr(ppu: &mut NesPPU, addr: u16, data: u8) {
+        ppu.read_status();
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+        ppu.write_to_ppu_data(data);
+    }
+
+    fn read_chr(ppu: &mut NesPPU, addr: u16) -> u8 {
+        ppu.read_status();
+        ppu.write_to_ppu_addr((addr >> 8) as u8);
+        ppu.write_to_ppu_addr(addr as u8);
+        ppu.read_data();
+        ppu.read_data()
+    }
+
+    #[test]
+    fn chr_ram_is_writable() {
+        let mut ppu = NesPPU::with_chr_ram(0x2000, Mirroring::HORIZONTAL);
+        write_chr(&mut ppu, 0x1234, 0x66);
+        assert_eq!(read_chr(&mut ppu, 0x1234), 0x66);
+    }
+
+    #[test]
+    fn chr_rom_ignores_writes() {
+        let mut ppu = NesPPU::new(vec![0x11; 0x2000], Mirroring::HORIZONTAL);
+        write_chr(&mut ppu, 0x1234, 0x66);
+        assert_eq!(read_chr(&mut ppu, 0x1234), 0x11);
+    }
+
+    #[test]
+    fn small_chr_is_mirrored() {
+        let mut ppu = NesPPU::with_chr_ram(0x800, Mirroring::HORIZONTAL);
+        write_chr(&mut ppu, 0x0010, 0x66);
+        assert_eq!(read_chr(&mut ppu, 0x1810), 0x66);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::logging::verbose;
 use crate::ppu::registers::w::WREG;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -25,7 +26,7 @@ impl PPUADDR {
     }
 
     pub fn get_u8(&self) -> u8 {
-        println!("reading address, latch: {}", self.latch_state.borrow().is_set());
+        verbose!(target: "ppu", "reading address, latch: {}", self.latch_state.borrow().is_set());
         if self.latch_state.borrow().is_set() {
             self.value.0
         } else {
@@ -42,7 +43,7 @@ impl PPUADDR {
     }
 
     fn update_ping(&mut self, data: u8, ping: bool) {
-        println!("writting address, latch: {}", self.latch_state.borrow().is_set());
+        verbose!(target: "ppu", "writing address, latch: {}", self.latch_state.borrow().is_set());
         if self.latch_state.borrow().is_set() {
             self.value.0 = data;
         } else {
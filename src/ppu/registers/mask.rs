@@ -27,6 +27,12 @@ bitflags! {
 
 }
 
+impl Default for PPUMASK {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PPUMASK {
     pub fn new() -> Self {
         PPUMASK::from_bits_truncate(0b0000_0000)
@@ -1,3 +1,4 @@
+use crate::logging::verbose;
 use crate::ppu::registers::w::WREG;
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -29,7 +30,7 @@ impl PPUSCROLL {
     }
 
     fn set_ping(&mut self, data: u8, ping: bool) {
-        println!("writting scroll, latch: {}", self.latch_state.borrow().is_set());
+        verbose!(target: "ppu", "writing scroll, latch: {}", self.latch_state.borrow().is_set());
         if self.latch_state.borrow().is_set() {
             self.scroll_x = data;
         } else {
@@ -45,7 +46,7 @@ impl PPUSCROLL {
     }
 
     pub fn get_u8(&mut self) -> u8 {
-        println!("reading scroll, latch: {}", self.latch_state.borrow().is_set());
+        verbose!(target: "ppu", "reading scroll, latch: {}", self.latch_state.borrow().is_set());
         if self.latch_state.borrow().is_set() {
             self.scroll_x
         } else {
@@ -0,0 +1,98 @@
+use crate::apu::NTSC_CPU_CLOCK;
+use crate::cartridge::header::Timing;
+
+// Which console the game is timed for. The CPU runs the same code on all of
+// them, only the clocks around it differ
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    // The famiclone: PAL's 312 scanline frame with NTSC's 3 dots per CPU
+    // cycle, the CPU divides the master clock by 15 where NTSC does by 12
+    // and PAL by 16. Vblank gets the extra scanlines and the APU keeps
+    // NTSC's tables
+    Dendy,
+}
+
+impl Region {
+    // Multi-region games run as NTSC
+    pub fn from_timing(timing: Timing) -> Region {
+        match timing {
+            Timing::Ntsc | Timing::Multi => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    // What a header would say to get this region
+    pub fn timing(self) -> Timing {
+        match self {
+            Region::Ntsc => Timing::Ntsc,
+            Region::Pal => Timing::Pal,
+            Region::Dendy => Timing::Dendy,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    // CPU cycles per second
+    pub fn cpu_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_CPU_CLOCK,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    // PPU dots per CPU cycle as a fraction, 3.2 on PAL
+    pub fn dots_per_cycle(self) -> (u32, u32) {
+        match self {
+            Region::Pal => (16, 5),
+            Region::Ntsc | Region::Dendy => (3, 1),
+        }
+    }
+
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // The scanline vblank starts on, Dendy idles 51 lines after the picture
+    // where PAL idles 1
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Whether the APU uses the PAL tables, Dendy kept NTSC's
+    pub fn pal_apu(self) -> bool {
+        self == Region::Pal
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frame_rates() {
+        for (region, rate) in [(Region::Ntsc, 60.1), (Region::Pal, 50.0), (Region::Dendy, 50.0)] {
+            let (dots, cycles) = region.dots_per_cycle();
+            let dots_per_frame = 341.0 * region.scanlines() as f64;
+            let frames = region.cpu_clock() * dots as f64 / cycles as f64 / dots_per_frame;
+            assert!((frames - rate).abs() < 0.1, "{:?} runs at {} Hz", region, frames);
+        }
+    }
+}
@@ -1,7 +1,16 @@
+use std::io::{self, Write};
+
+#[derive(Clone)]
 pub struct Frame {
     pub data: Vec<u8>,
 }
 
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Frame {
     const WIDTH: usize = 256;
     const HEIGHT: usize= 240;
@@ -20,4 +29,55 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+
+    // FNV-1a over the RGB data. Unlike std's hashers it is fixed across
+    // platforms and Rust versions, so hashes can be checked in
+    pub fn hash(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    pub fn write_ppm<W: Write>(&self, mut out: W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", Frame::WIDTH, Frame::HEIGHT)?;
+        out.write_all(&self.data)
+    }
+
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(out, Frame::WIDTH as u32, Frame::HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_is_stable() {
+        let mut frame = Frame::new();
+        assert_eq!(frame.hash(), 0x96d6_3225_ea92_6325);
+
+        frame.set_pixel(10, 20, (1, 2, 3));
+        assert_ne!(frame.hash(), Frame::new().hash());
+    }
+
+    #[test]
+    fn writes_ppm() {
+        let mut out = Vec::new();
+        Frame::new().write_ppm(&mut out).unwrap();
+        assert!(out.starts_with(b"P6\n256 240\n255\n"));
+        assert_eq!(out.len(), 15 + 256 * 240 * 3);
+    }
+
+    #[test]
+    fn writes_png() {
+        let mut out = Vec::new();
+        Frame::new().write_png(&mut out).unwrap();
+        assert!(out.starts_with(&[0x89, b'P', b'N', b'G']));
+    }
+}
@@ -12,7 +12,10 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
         let tile = ppu.vram[i] as u16;
         let tile_x = i % 32;
         let tile_y = i / 32;
-        let tile = &ppu.chr_rom[(bank + tile * 16) as usize..=(bank + tile * 16 + 15) as usize];
+        let start = (bank + tile * 16) as usize;
+        let Some(tile) = ppu.chr.get(start..start + 16) else {
+            continue;
+        };
 
         for y in 0..=7 {
             let mut upper = tile[y];
@@ -20,8 +23,8 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
 
             for x in (0..=7).rev() {
                 let value = (1 & upper) << 1 | (1 & lower);
-                upper = upper >> 1;
-                lower = lower >> 1;
+                upper >>= 1;
+                lower >>= 1;
 
                 let rgb = match value {
                     0 => palette::SYSTEM_PALETTE[0x01],
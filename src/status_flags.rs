@@ -0,0 +1,77 @@
+use bitflags::bitflags;
+
+bitflags! {
+   // 7  bit  0
+   // ---- ----
+   // NV1B DIZC
+   // |||| ||||
+   // |||| |||+- Carry
+   // |||| ||+-- Zero
+   // |||| |+--- Interrupt Disable
+   // |||| +---- Decimal (stored but ignored by the NES)
+   // |||+------ Break, only exists in the copy pushed to the stack
+   // ||+------- Unused, always pushed as 1
+   // |+-------- Overflow
+   // +--------- Negative
+
+   pub struct StatusFlags: u8 {
+        const CARRY             = 0b0000_0001;
+        const ZERO              = 0b0000_0010;
+        const INTERRUPT_DISABLE = 0b0000_0100;
+        const DECIMAL           = 0b0000_1000;
+        const BREAK             = 0b0001_0000;
+        const UNUSED            = 0b0010_0000;
+        const OVERFLOW          = 0b0100_0000;
+        const NEGATIVE          = 0b1000_0000;
+   }
+}
+
+impl StatusFlags {
+    // Power up and reset state
+    pub fn new() -> Self {
+        StatusFlags::INTERRUPT_DISABLE | StatusFlags::UNUSED
+    }
+
+    pub fn carry(&self) -> u8 {
+        (*self & StatusFlags::CARRY).bits()
+    }
+
+    // PHP and BRK push with B set, NMI and IRQ push with it clear
+    pub fn to_stack(self, brk: bool) -> u8 {
+        let mut pushed = self | StatusFlags::UNUSED;
+        pushed.set(StatusFlags::BREAK, brk);
+        pushed.bits()
+    }
+
+    // PLP and RTI ignore B and the unused bit of the pulled byte
+    pub fn from_stack(&mut self, data: u8) {
+        let ignored = StatusFlags::BREAK | StatusFlags::UNUSED;
+        let pulled = StatusFlags::from_bits_truncate(data) - ignored;
+        *self = pulled | (*self & ignored);
+    }
+}
+
+impl Default for StatusFlags {
+    fn default() -> Self {
+        StatusFlags::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pushes_b_only_for_brk() {
+        let status = StatusFlags::new() | StatusFlags::CARRY;
+        assert_eq!(status.to_stack(true), 0b0011_0101);
+        assert_eq!(status.to_stack(false), 0b0010_0101);
+    }
+
+    #[test]
+    fn pulls_ignore_b_and_unused() {
+        let mut status = StatusFlags::new();
+        status.from_stack(0b1101_0011);
+        assert_eq!(status.bits(), 0b1110_0011);
+    }
+}
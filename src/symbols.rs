@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// FCEUX names its per-bank label files after 16K PRG banks
+const NL_BANK_SIZE: usize = 0x4000;
+
+/// Labels loaded from cc65 `.dbg`, FCEUX `.nl` or Mesen `.mlb` files.
+///
+/// Addresses below $8000 are plain CPU addresses. Labels that live in PRG ROM
+/// are keyed by their offset inside the PRG data, so the same CPU address can
+/// resolve to different names depending on the bank currently mapped there.
+#[derive(Default, Debug)]
+pub struct SymbolTable {
+    cpu: HashMap<u16, String>,
+    prg: HashMap<usize, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            cpu: HashMap::new(),
+            prg: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_empty() && self.prg.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cpu.len() + self.prg.len()
+    }
+
+    pub fn add_cpu_label(&mut self, addr: u16, name: &str) {
+        self.cpu.insert(addr, name.to_string());
+    }
+
+    pub fn add_prg_label(&mut self, offset: usize, name: &str) {
+        self.prg.insert(offset, name.to_string());
+    }
+
+    /// Resolves `addr` to a label. `prg_offset` is where `addr` lands in PRG
+    /// ROM under the current banking, as reported by `BusOP::prg_rom_offset`.
+    pub fn lookup(&self, addr: u16, prg_offset: Option<usize>) -> Option<&str> {
+        prg_offset
+            .and_then(|offset| self.prg.get(&offset))
+            .or_else(|| self.cpu.get(&addr))
+            .map(|s| s.as_str())
+    }
+
+    /// Picks up every symbol file sitting next to `rom_path`:
+    /// `game.dbg`, `game.mlb`, `game.nes.ram.nl` and `game.nes.<bank>.nl`.
+    pub fn load_for_rom(rom_path: &Path) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+
+        let dbg = rom_path.with_extension("dbg");
+        if dbg.exists() {
+            table.parse_dbg(&read_to_string(&dbg)?)?;
+        }
+
+        let mlb = rom_path.with_extension("mlb");
+        if mlb.exists() {
+            table.parse_mlb(&read_to_string(&mlb)?)?;
+        }
+
+        let file_name = match rom_path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => return Ok(table),
+        };
+        let ram_nl = rom_path.with_file_name(format!("{}.ram.nl", file_name));
+        if ram_nl.exists() {
+            table.parse_nl(&read_to_string(&ram_nl)?, None)?;
+        }
+
+        let mut bank = 0;
+        loop {
+            let bank_nl = rom_path.with_file_name(format!("{}.{:X}.nl", file_name, bank));
+            if !bank_nl.exists() {
+                break;
+            }
+            table.parse_nl(&read_to_string(&bank_nl)?, Some(bank))?;
+            bank += 1;
+        }
+
+        Ok(table)
+    }
+
+    /// Parses an FCEUX name list. Lines look like `$C000#reset#comment`.
+    /// `bank` is `None` for `.ram.nl` files and the 16K bank number otherwise.
+    pub fn parse_nl(&mut self, text: &str, bank: Option<usize>) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if !line.starts_with('$') {
+                continue;
+            }
+            let mut fields = line[1..].split('#');
+            let addr_field = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            // Arrays are written as `$0200/10#buffer#`
+            let (addr_field, count) = match addr_field.split_once('/') {
+                Some((addr, count)) => (addr, parse_hex(count, n)? as usize),
+                None => (addr_field, 1),
+            };
+            let addr = parse_hex(addr_field, n)? as u16;
+
+            for i in 0..count.max(1) {
+                let addr = addr.wrapping_add(i as u16);
+                match bank {
+                    Some(bank) if addr >= 0x8000 => {
+                        let offset = bank * NL_BANK_SIZE + (addr as usize % NL_BANK_SIZE);
+                        self.add_prg_label(offset, name);
+                    }
+                    _ => self.add_cpu_label(addr, name),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a Mesen label file. Both the original single-letter memory types
+    /// (`P:0010:reset`) and the Mesen 2 names (`NesPrgRom:0010:reset`) are
+    /// accepted. Ranges such as `R:0200-02FF:oam` label every byte.
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or("");
+            let addr_field = fields.next().unwrap_or("");
+            let name = fields.next().unwrap_or("").trim();
+            if name.is_empty() {
+                continue;
+            }
+
+            let (start, end) = match addr_field.split_once('-') {
+                Some((start, end)) => (parse_hex(start, n)?, parse_hex(end, n)?),
+                None => {
+                    let addr = parse_hex(addr_field, n)?;
+                    (addr, addr)
+                }
+            };
+
+            for value in start..=end {
+                match kind {
+                    "P" | "NesPrgRom" => self.add_prg_label(value as usize, name),
+                    "R" | "NesInternalRam" => self.add_cpu_label((value & 0x07FF) as u16, name),
+                    "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                        self.add_cpu_label(0x6000 + (value & 0x1FFF) as u16, name)
+                    }
+                    "G" | "NesMemory" => self.add_cpu_label(value as u16, name),
+                    // CHR and PPU labels don't show up in CPU traces
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the debug info written by `ld65 --dbgfile`. Symbols in segments
+    /// that were written to the ROM image (`ooffs=`) become PRG labels, the rest
+    /// (zero page, BSS, equates of addresses) are plain CPU labels.
+    pub fn parse_dbg(&mut self, text: &str) -> Result<(), String> {
+        // segment id -> (cpu start address, offset into PRG ROM)
+        let mut segments: HashMap<usize, (u32, Option<usize>)> = HashMap::new();
+        let mut symbols: Vec<(String, u32, Option<usize>)> = Vec::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let (kind, rest) = match line.split_once(char::is_whitespace) {
+                Some(split) => split,
+                None => continue,
+            };
+            let attrs = parse_dbg_attrs(rest);
+
+            match kind {
+                "seg" => {
+                    let id = dbg_number(&attrs, "id", n)? as usize;
+                    let start = dbg_number(&attrs, "start", n)?;
+                    // Output offsets include the 16-byte iNES header
+                    let prg_offset = match attrs.get("ooffs") {
+                        Some(ooffs) => {
+                            let ooffs = parse_number(ooffs, n)? as usize;
+                            Some(ooffs.saturating_sub(16))
+                        }
+                        None => None,
+                    };
+                    segments.insert(id, (start, prg_offset));
+                }
+                "sym" => {
+                    if attrs.get("type").map(|t| t.as_str()) != Some("lab") {
+                        continue;
+                    }
+                    let name = match attrs.get("name") {
+                        Some(name) => name.trim_matches('"').to_string(),
+                        None => continue,
+                    };
+                    let val = dbg_number(&attrs, "val", n)?;
+                    let seg = match attrs.get("seg") {
+                        Some(seg) => Some(parse_number(seg, n)? as usize),
+                        None => None,
+                    };
+                    symbols.push((name, val, seg));
+                }
+                _ => {}
+            }
+        }
+
+        for (name, val, seg) in symbols {
+            let segment = seg.and_then(|id| segments.get(&id));
+            match segment {
+                Some((start, Some(prg_offset))) if val >= 0x8000 => {
+                    // A value before its own segment can't be placed in PRG
+                    // ROM, the file is broken there
+                    if let Some(delta) = val.checked_sub(*start) {
+                        self.add_prg_label(prg_offset + delta as usize, &name);
+                    }
+                }
+                _ => self.add_cpu_label(val as u16, &name),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_to_string(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+}
+
+fn parse_hex(field: &str, line: usize) -> Result<u32, String> {
+    u32::from_str_radix(field.trim(), 16)
+        .map_err(|_| format!("Invalid address '{}' on line {}", field, line + 1))
+}
+
+fn parse_number(field: &str, line: usize) -> Result<u32, String> {
+    let field = field.trim();
+    let parsed = match field.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => field.parse::<u32>(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{}' on line {}", field, line + 1))
+}
+
+fn dbg_number(attrs: &HashMap<String, String>, key: &str, line: usize) -> Result<u32, String> {
+    match attrs.get(key) {
+        Some(value) => parse_number(value, line),
+        None => Err(format!("Missing '{}' on line {}", key, line + 1)),
+    }
+}
+
+// `id=3,name="CODE",start=0x00C000` -> {id: 3, name: "CODE", start: 0x00C000}
+// Quoted values may contain commas, so this can't just split on ','.
+fn parse_dbg_attrs(text: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut quoted = false;
+
+    for c in text.trim().chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                value.push(c);
+            }
+            '=' if !in_value => in_value = true,
+            ',' if !quoted => {
+                attrs.insert(key.clone(), value.clone());
+                key.clear();
+                value.clear();
+                in_value = false;
+            }
+            _ if in_value => value.push(c),
+            _ => key.push(c),
+        }
+    }
+    if !key.is_empty() {
+        attrs.insert(key, value);
+    }
+    attrs
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nl_ram_and_banks() {
+        let mut table = SymbolTable::new();
+        table.parse_nl("$0000#tmp#scratch\n$0200/4#oam#\n", None).unwrap();
+        table.parse_nl("$8000#reset#\n", Some(0)).unwrap();
+        table.parse_nl("$8000#bank1_entry#\n", Some(1)).unwrap();
+
+        assert_eq!(table.lookup(0x0000, None), Some("tmp"));
+        assert_eq!(table.lookup(0x0203, None), Some("oam"));
+        assert_eq!(table.lookup(0x0204, None), None);
+        assert_eq!(table.lookup(0x8000, Some(0x0000)), Some("reset"));
+        assert_eq!(table.lookup(0x8000, Some(0x4000)), Some("bank1_entry"));
+    }
+
+    #[test]
+    fn mlb_memory_types() {
+        let mut table = SymbolTable::new();
+        table
+            .parse_mlb("P:0010:nmi:comment\nR:0010-0011:ptr\nS:0000:save\nG:2002:PPUSTATUS\n")
+            .unwrap();
+
+        assert_eq!(table.lookup(0xC010, Some(0x0010)), Some("nmi"));
+        assert_eq!(table.lookup(0x0011, None), Some("ptr"));
+        assert_eq!(table.lookup(0x6000, None), Some("save"));
+        assert_eq!(table.lookup(0x2002, None), Some("PPUSTATUS"));
+    }
+
+    #[test]
+    fn dbg_segments() {
+        let dbg = "version\tmajor=2,minor=0\n\
+            seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw\n\
+            seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400\n\
+            sym\tid=0,name=\"frame\",addrsize=zeropage,scope=0,def=1,val=0x2,seg=0,type=lab\n\
+            sym\tid=1,name=\"main\",addrsize=absolute,scope=0,def=2,val=0xC010,seg=1,type=lab\n\
+            sym\tid=2,name=\"SIZE\",addrsize=zeropage,scope=0,def=3,val=0x10,type=equ\n\
+            sym\tid=3,name=\"broken\",addrsize=absolute,scope=0,def=4,val=0x8000,seg=1,type=lab\n";
+
+        let mut table = SymbolTable::new();
+        table.parse_dbg(dbg).unwrap();
+
+        assert_eq!(table.lookup(0x0002, None), Some("frame"));
+        assert_eq!(table.lookup(0xC010, Some(0x4010)), Some("main"));
+        assert_eq!(table.lookup(0x0010, None), None);
+        assert_eq!(table.lookup(0x8000, Some(0)), None);
+    }
+}
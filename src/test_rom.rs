@@ -0,0 +1,176 @@
+use crate::bus::{Bus, BusOP};
+use crate::cartridge::Rom;
+use crate::cpu::{CPU, Mem};
+
+// Blargg's test ROMs report through save RAM:
+//   $6000       status: $80 running, $81 reset requested, $00-$7F result
+//   $6001-$6003 $DE $B0 $61 once the status byte is valid
+//   $6004-      zero terminated text output
+const STATUS_ADDR: u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_ADDR: u16 = 0x6004;
+const TEXT_END: u16 = 0x7FFF;
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+
+// Roughly one NTSC second of CPU time
+pub const CPU_CYCLES_PER_SECOND: usize = 1_789_773;
+// ROMs asking for a reset want it at least 100ms later
+const RESET_DELAY: usize = CPU_CYCLES_PER_SECOND / 10;
+// How often the status byte is polled
+const POLL_INTERVAL: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum TestRomStatus {
+    Passed,
+    Failed(u8),
+    TimedOut,
+}
+
+#[derive(Debug)]
+pub struct TestRomResult {
+    pub status: TestRomStatus,
+    pub message: String,
+    pub cycles: usize,
+}
+
+impl TestRomResult {
+    pub fn passed(&self) -> bool {
+        self.status == TestRomStatus::Passed
+    }
+}
+
+fn has_signature<T: BusOP>(cpu: &mut CPU<T>) -> bool {
+    (0..3).all(|i| cpu.bus.mem_read(SIGNATURE_ADDR + i) == SIGNATURE[i as usize])
+}
+
+fn read_message<T: BusOP>(cpu: &mut CPU<T>) -> String {
+    let mut text = Vec::new();
+    for addr in TEXT_ADDR..=TEXT_END {
+        let byte = cpu.bus.mem_read(addr);
+        if byte == 0 {
+            break;
+        }
+        text.push(byte);
+    }
+    String::from_utf8_lossy(&text).trim().to_string()
+}
+
+/// Runs a test ROM headlessly until it reports a result through $6000 or
+/// `timeout_cycles` CPU cycles have gone by.
+pub fn run_test_rom(rom: Rom, timeout_cycles: usize) -> Result<TestRomResult, String> {
+    if rom.header.mapper != 0 {
+        return Err(format!("Mapper {} is not supported", rom.header.mapper));
+    }
+
+    let mut cpu = CPU::new(Bus::new(rom, |_| {}));
+    cpu.reset();
+
+    let mut next_poll = 0;
+    let mut reset_at: Option<usize> = None;
+
+    loop {
+        cpu.step(|_| {}).map_err(|err| err.to_string())?;
+        let cycles = cpu.bus.cycles();
+
+        if let Some(at) = reset_at
+            && cycles >= at
+        {
+            reset_at = None;
+            cpu.reset();
+        }
+
+        if cycles >= next_poll {
+            next_poll = cycles + POLL_INTERVAL;
+
+            if has_signature(&mut cpu) {
+                match cpu.bus.mem_read(STATUS_ADDR) {
+                    STATUS_RUNNING => {}
+                    STATUS_RESET => {
+                        if reset_at.is_none() {
+                            reset_at = Some(cycles + RESET_DELAY);
+                        }
+                    }
+                    code => {
+                        let status = match code {
+                            0 => TestRomStatus::Passed,
+                            _ => TestRomStatus::Failed(code),
+                        };
+                        return Ok(TestRomResult {
+                            status,
+                            message: read_message(&mut cpu),
+                            cycles,
+                        });
+                    }
+                }
+            }
+        }
+
+        if cycles >= timeout_cycles {
+            return Ok(TestRomResult {
+                status: TestRomStatus::TimedOut,
+                message: read_message(&mut cpu),
+                cycles,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::cartridge::header::RomHeader;
+
+    // Writes `text` to $6004, the signature, then `result` to $6000 and spins
+    fn reporting_rom(text: &str, result: u8) -> Rom {
+        let mut code: Vec<u8> = Vec::new();
+        for (i, byte) in text.bytes().chain(std::iter::once(0)).enumerate() {
+            let addr = TEXT_ADDR + i as u16;
+            code.extend([0xA9, byte, 0x8D, addr as u8, (addr >> 8) as u8]);
+        }
+        for (i, byte) in SIGNATURE.iter().enumerate() {
+            let addr = SIGNATURE_ADDR + i as u16;
+            code.extend([0xA9, *byte, 0x8D, addr as u8, (addr >> 8) as u8]);
+        }
+        code.extend([0xA9, result, 0x8D, 0x00, 0x60]);
+        let spin = 0x8000 + code.len() as u16;
+        code.extend([0x4C, spin as u8, (spin >> 8) as u8]);
+
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[..code.len()].copy_from_slice(&code);
+        // reset vector -> $8000
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0x80;
+
+        Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            trainer: None,
+            header: RomHeader::new(0, Mirroring::HORIZONTAL),
+            title: None,
+        }
+    }
+
+    #[test]
+    fn reports_pass() {
+        let result = run_test_rom(reporting_rom("Passed", 0), CPU_CYCLES_PER_SECOND).unwrap();
+        assert_eq!(result.status, TestRomStatus::Passed);
+        assert_eq!(result.message, "Passed");
+    }
+
+    #[test]
+    fn reports_failure_code() {
+        let result = run_test_rom(reporting_rom("Failed #3", 3), CPU_CYCLES_PER_SECOND).unwrap();
+        assert_eq!(result.status, TestRomStatus::Failed(3));
+        assert_eq!(result.message, "Failed #3");
+    }
+
+    #[test]
+    fn times_out_while_running() {
+        let result = run_test_rom(reporting_rom("", STATUS_RUNNING), 10_000).unwrap();
+        assert_eq!(result.status, TestRomStatus::TimedOut);
+    }
+}
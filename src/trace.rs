@@ -1,55 +1,104 @@
-use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 
 use crate::bus::BusOP;
-use crate::cpu::{AddressingMode, CPU, Mem};
+use crate::cpu::{AddressingMode, CPU};
 use crate::opcodes;
+use crate::status_flags::StatusFlags;
+use crate::symbols::SymbolTable;
 
-const POS_INSTRUCTION_COL : usize = 16;
-const POS_REGISTER_COL : usize = 48;
+// Trace banks are counted in 16K units, like FCEUX's .nl files
+const TRACE_BANK_SIZE: usize = 0x4000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TraceFormat {
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    #[default]
+    Nintendulator,
+    // c7           A:00 X:00 Y:00 S:FD P:nvubdIzc  $C000:4C F5 C5  JMP $C5F5
+    Fceux,
+    // C000  $4C $F5 $C5  JMP $C5F5                       A:00 X:00 Y:00 P:nvUbdIzc SP:FD CYC:21  SL:0   FC:0 CPU Cycle:7
+    Mesen,
+}
+
+impl TraceFormat {
+    // Columns where the instruction and the registers start, counted from
+    // the address. FCEUX has its registers in front instead
+    fn columns(&self) -> (usize, usize) {
+        match self {
+            TraceFormat::Nintendulator => (16, 48),
+            TraceFormat::Fceux => (16, 48),
+            TraceFormat::Mesen => (19, 51),
+        }
+    }
+}
+
+struct Disassembly {
+    bytes: Vec<u8>,
+    unofficial: bool,
+    text: String,
+}
 
 fn pad(line: &mut String, padding: usize) {
     line.push_str(&" ".repeat(padding.saturating_sub(line.len())));
 }
 
-pub fn trace<T: BusOP>(cpu: &mut CPU<T>) -> String {
-    let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
-    let code = cpu.mem_read(cpu.program_counter);
+// `$C5F5`, or the label for it when one is known
+fn operand<T: BusOP>(cpu: &CPU<T>, symbols: Option<&SymbolTable>, addr: u16, zero_page: bool) -> String {
+    let label = symbols.and_then(|s| s.lookup(addr, cpu.bus.prg_rom_offset(addr)));
+    match label {
+        Some(name) => name.to_string(),
+        None if zero_page => format!("${:02X}", addr),
+        None => format!("${:04X}", addr),
+    }
+}
+
+// NV-BDIZC with set flags in upper case, e.g. `nvUbdIzc`
+fn flags(status: StatusFlags) -> String {
+    "NVUBDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if status.bits() & (0b1000_0000 >> i) != 0 {
+                c
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
 
-    let opcode = opcodes
-        .get(&code)
-        .expect(&format!("OpCode {:x} is not recognized", code));
+fn peek_u16<T: BusOP>(cpu: &mut CPU<T>, addr: u16) -> u16 {
+    let lo = cpu.bus.peek(addr) as u16;
+    let hi = cpu.bus.peek(addr.wrapping_add(1)) as u16;
+    hi << 8 | lo
+}
 
-    let mut line = String::new();
-    line.push_str(&format!("{:04X}  ", cpu.program_counter));
-    // line.push_str(&format!("{:X} ", opcode.code));
-    let mut codes: Vec<u8> = Vec::new();
+fn disassemble<T: BusOP>(cpu: &mut CPU<T>, symbols: Option<&SymbolTable>) -> Disassembly {
+    let code = cpu.bus.peek(cpu.program_counter);
 
+    let opcode = opcodes::OPCODE_TABLE[code as usize]
+        .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+
+    let mut codes: Vec<u8> = Vec::new();
     for i in 0..=opcode.len - 1 {
-        let code = cpu.mem_read(cpu.program_counter.wrapping_add(i.into()) as u16);
-        line.push_str(&format!("{:02X} ", code));
+        let code = cpu.bus.peek(cpu.program_counter.wrapping_add(i.into()));
         codes.push(code);
     }
 
-    // Pad when there are less than 2 arguments for opcode
-    // for _i in 1..=3 - codes.len() {
-    //     line.push_str(&"   ".to_string());
-    // }
-    pad(&mut line, POS_INSTRUCTION_COL - if opcode.unofficial {1} else {0});
-
-    if opcode.unofficial {
-        line.push_str(&"*");
-    }
-
-    line.push_str(&format!("{} ", opcode.mneumonic));
+    let mut line = String::new();
+    line.push_str(&format!("{} ", opcode.instruction));
 
     match opcode.mode {
         AddressingMode::Immediate => {
             line.push_str(&format!("#${:02X} ", codes[1]));
         }
         AddressingMode::ZeroPage => {
-            line.push_str(&format!("${:02X} ", codes[1]));
+            line.push_str(&format!("{} ", operand(cpu, symbols, codes[1] as u16, true)));
 
-            let val = cpu.mem_read(codes[1] as u16);
+            let val = cpu.bus.peek(codes[1] as u16);
             line.push_str(&format!("= {:02X} ", val));
         }
         AddressingMode::Relative => {
@@ -57,72 +106,72 @@ pub fn trace<T: BusOP>(cpu: &mut CPU<T>) -> String {
                 .program_counter
                 .wrapping_add(2) // +2 because the PC is one off
                 .wrapping_add_signed(codes[1] as i8 as i16);
-            line.push_str(&format!("${:02X} ", val));
+            line.push_str(&format!("{} ", operand(cpu, symbols, val, false)));
         }
         AddressingMode::ZeroPage_X => {
-            line.push_str(&format!("${:02X},X @ ", codes[1]));
+            line.push_str(&format!("{},X @ ", operand(cpu, symbols, codes[1] as u16, true)));
 
             let pos = codes[1].wrapping_add(cpu.register_x);
-            let val = cpu.mem_read(pos as u16);
+            let val = cpu.bus.peek(pos as u16);
 
             line.push_str(&format!("{:02X} = {:02X}", pos, val))
         }
         AddressingMode::ZeroPage_Y => {
-            line.push_str(&format!("${:02X},Y @ ", codes[1]));
+            line.push_str(&format!("{},Y @ ", operand(cpu, symbols, codes[1] as u16, true)));
 
             let pos = codes[1].wrapping_add(cpu.register_y);
-            let val = cpu.mem_read(pos as u16);
+            let val = cpu.bus.peek(pos as u16);
 
             line.push_str(&format!("{:02X} = {:02X}", pos, val))
         }
         AddressingMode::Absolute => {
-            line.push_str(&format!("${:02X}{:02X}", codes[2], codes[1]));
+            let addr = (codes[2] as u16) << 8 | (codes[1] as u16);
+            line.push_str(&operand(cpu, symbols, addr, false));
             if code != 0x4C && code != 0x20 {
-                let addr = (codes[2] as u16) << 8 | (codes[1] as u16);
-                let val = cpu.mem_read(addr);
+                let val = cpu.bus.peek(addr);
                 line.push_str(&format!(" = {:02X}", val));
             }
         }
         AddressingMode::Absolute_X => {
             let base = (codes[2] as u16) << 8 | (codes[1] as u16);
-            line.push_str(&format!("${:04X},X @ ", base));
+            line.push_str(&format!("{},X @ ", operand(cpu, symbols, base, false)));
             let addr = base.wrapping_add(cpu.register_x as u16);
-            let val = cpu.mem_read(addr);
+            let val = cpu.bus.peek(addr);
 
             line.push_str(&format!("{:04X} = {:02X}", addr, val))
         }
         AddressingMode::Absolute_Y => {
             let base = (codes[2] as u16) << 8 | (codes[1] as u16);
-            line.push_str(&format!("${:04X},Y @ ", base));
+            line.push_str(&format!("{},Y @ ", operand(cpu, symbols, base, false)));
 
             let addr = base.wrapping_add(cpu.register_y as u16);
-            let val = cpu.mem_read(addr);
+            let val = cpu.bus.peek(addr);
 
             line.push_str(&format!("{:04X} = {:02X}", addr, val))
         }
         AddressingMode::Indirect_X => {
             if code != 0x6C {
-                line.push_str(&format!("(${:02X},X) @ ", codes[1]));
+                line.push_str(&format!("({},X) @ ", operand(cpu, symbols, codes[1] as u16, true)));
 
                 let base = codes[1];
                 let ptr = base.wrapping_add(cpu.register_x);
-                let lo = cpu.mem_read(ptr as u16);
-                let hi = cpu.mem_read(ptr.wrapping_add(1) as u16);
+                let lo = cpu.bus.peek(ptr as u16);
+                let hi = cpu.bus.peek(ptr.wrapping_add(1) as u16);
                 let pos = (hi as u16) << 8 | (lo as u16);
-                let val = cpu.mem_read_u16(pos);
+                let val = peek_u16(cpu, pos);
 
                 line.push_str(&format!("{:02X} = {:04X} = {:02X}    ", ptr, pos, val))
-            } 
+            }
         }
 
         AddressingMode::Indirect_Y => {
-            line.push_str(&format!("(${:02X}),Y ", codes[1]));
+            line.push_str(&format!("({}),Y ", operand(cpu, symbols, codes[1] as u16, true)));
 
-            let lo = cpu.mem_read(codes[1] as u16);
-            let hi = cpu.mem_read(codes[1].wrapping_add(1) as u16);
+            let lo = cpu.bus.peek(codes[1] as u16);
+            let hi = cpu.bus.peek(codes[1].wrapping_add(1) as u16);
             let deref_base = (hi as u16) << 8 | (lo as u16);
             let deref = deref_base.wrapping_add(cpu.register_y as u16);
-            let val = cpu.mem_read_u16(deref);
+            let val = peek_u16(cpu, deref);
 
             line.push_str(&format!(
                 "= {:04X} @ {:04X} = {:02X}",
@@ -130,29 +179,333 @@ pub fn trace<T: BusOP>(cpu: &mut CPU<T>) -> String {
             ))
         }
 
-        AddressingMode::Indirect => { 
+        AddressingMode::Indirect => {
             let addr = ((codes[2] as u16) << 8) | (codes[1] as u16);
 
                 /* Implements the page bug of the jump */
             let val = if addr & 0x00FF == 0x00FF {
-                    let lo = cpu.mem_read(addr);
-                    let hi = cpu.mem_read(addr & 0xFF00);
+                    let lo = cpu.bus.peek(addr);
+                    let hi = cpu.bus.peek(addr & 0xFF00);
                     (hi as u16) << 8 | (lo as u16)
                 } else {
-                    cpu.mem_read_u16(addr)
+                    peek_u16(cpu, addr)
                 };
 
-            line.push_str(&format!("(${:04X}) = {:04X}", addr,val))
+            line.push_str(&format!("({}) = {:04X}", operand(cpu, symbols, addr, false), val))
         }
         AddressingMode::NoneAddressing => {
         }
     }
-    pad(&mut line, POS_REGISTER_COL);
 
-    line.push_str(&format!(
-        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
-        cpu.register_a, cpu.register_x, cpu.register_y, cpu.status, cpu.stack_pointer, cpu.bus.cycles()
-    ));
+    Disassembly {
+        bytes: codes,
+        unofficial: opcode.unofficial,
+        text: line,
+    }
+}
+
+pub fn trace<T: BusOP>(cpu: &mut CPU<T>) -> String {
+    trace_with_symbols(cpu, None)
+}
+
+pub fn trace_with_symbols<T: BusOP>(cpu: &mut CPU<T>, symbols: Option<&SymbolTable>) -> String {
+    trace_with_format(cpu, TraceFormat::Nintendulator, false, symbols)
+}
+
+/// Formats the instruction at the program counter. `ppu_columns` adds the
+/// scanline and dot (the frame for FCEUX, both for Mesen) when the bus has
+/// a PPU.
+pub fn trace_with_format<T: BusOP>(
+    cpu: &mut CPU<T>,
+    format: TraceFormat,
+    ppu_columns: bool,
+    symbols: Option<&SymbolTable>,
+) -> String {
+    let asm = disassemble(cpu, symbols);
+    let ppu = if ppu_columns { cpu.bus.ppu_timing() } else { None };
+    let cycles = cpu.bus.cycles();
+    let (instruction_col, register_col) = format.columns();
+
+    let mut line = String::new();
+    // FCEUX puts its frame and cycle counters and the registers in front of
+    // the address. Its P never holds the unused and break bits
+    if format == TraceFormat::Fceux {
+        if let Some(ppu) = ppu {
+            line.push_str(&format!("f{:<6} ", ppu.frame));
+        }
+        line.push_str(&format!("c{:<11} ", cycles));
+        line.push_str(&format!(
+            "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ",
+            cpu.register_a,
+            cpu.register_x,
+            cpu.register_y,
+            cpu.stack_pointer,
+            flags(cpu.status.difference(StatusFlags::BREAK | StatusFlags::UNUSED))
+        ));
+    }
+    let prefix = line.len();
+
+    match format {
+        TraceFormat::Nintendulator => {
+            line.push_str(&format!("{:04X}  ", cpu.program_counter));
+            for code in &asm.bytes {
+                line.push_str(&format!("{:02X} ", code));
+            }
+        }
+        TraceFormat::Fceux => {
+            line.push_str(&format!("${:04X}:", cpu.program_counter));
+            for code in &asm.bytes {
+                line.push_str(&format!("{:02X} ", code));
+            }
+        }
+        TraceFormat::Mesen => {
+            line.push_str(&format!("{:04X}  ", cpu.program_counter));
+            for code in &asm.bytes {
+                line.push_str(&format!("${:02X} ", code));
+            }
+        }
+    }
+
+    pad(&mut line, prefix + instruction_col - if asm.unofficial { 1 } else { 0 });
+    if asm.unofficial {
+        line.push('*');
+    }
+    line.push_str(&asm.text);
+
+    match format {
+        TraceFormat::Nintendulator => {
+            pad(&mut line, prefix + register_col);
+            line.push_str(&format!(
+                "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} ",
+                cpu.register_a, cpu.register_x, cpu.register_y, cpu.status.bits(), cpu.stack_pointer
+            ));
+            if let Some(ppu) = ppu {
+                line.push_str(&format!("PPU:{:>3},{:>3} ", ppu.scanline, ppu.dot));
+            }
+            line.push_str(&format!("CYC:{}", cycles));
+        }
+        TraceFormat::Fceux => line.truncate(line.trim_end().len()),
+        TraceFormat::Mesen => {
+            pad(&mut line, prefix + register_col);
+            line.push_str(&format!(
+                "A:{:02X} X:{:02X} Y:{:02X} P:{} SP:{:02X} ",
+                cpu.register_a, cpu.register_x, cpu.register_y, flags(cpu.status), cpu.stack_pointer
+            ));
+            if let Some(ppu) = ppu {
+                line.push_str(&format!(
+                    "CYC:{:<3} SL:{:<3} FC:{} ",
+                    ppu.dot, ppu.scanline, ppu.frame
+                ));
+            }
+            line.push_str(&format!("CPU Cycle:{}", cycles));
+        }
+    }
 
     line
 }
+
+#[derive(Default)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+    pub ppu_columns: bool,
+    pub symbols: Option<SymbolTable>,
+    // Only log instructions whose PC falls in one of these ranges
+    pub pc_ranges: Vec<RangeInclusive<u16>>,
+    // Only log instructions running from these 16K PRG banks
+    pub banks: Vec<usize>,
+    // Start a new file after this many bytes, keeping `keep_files` old ones
+    // around as `trace.log.1`, `trace.log.2`...
+    pub max_file_size: Option<u64>,
+    pub keep_files: usize,
+}
+
+/// Writes trace lines to a file. Use it as the `step` callback:
+/// `cpu.step(|cpu| logger.log(cpu).unwrap())`.
+pub struct TraceLogger {
+    options: TraceOptions,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl TraceLogger {
+    pub fn new(path: &Path, options: TraceOptions) -> io::Result<TraceLogger> {
+        Ok(TraceLogger {
+            options,
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(path)?),
+            written: 0,
+        })
+    }
+
+    pub fn should_log<T: BusOP>(&self, cpu: &CPU<T>) -> bool {
+        let pc = cpu.program_counter;
+        if !self.options.pc_ranges.is_empty()
+            && !self.options.pc_ranges.iter().any(|range| range.contains(&pc))
+        {
+            return false;
+        }
+        if !self.options.banks.is_empty() {
+            return match cpu.bus.prg_rom_offset(pc) {
+                Some(offset) => self.options.banks.contains(&(offset / TRACE_BANK_SIZE)),
+                None => false,
+            };
+        }
+        true
+    }
+
+    pub fn log<T: BusOP>(&mut self, cpu: &mut CPU<T>) -> io::Result<()> {
+        if !self.should_log(cpu) {
+            return Ok(());
+        }
+        let line = trace_with_format(
+            cpu,
+            self.options.format,
+            self.options.ppu_columns,
+            self.options.symbols.as_ref(),
+        );
+        writeln!(self.writer, "{}", line)?;
+        self.written += line.len() as u64 + 1;
+
+        if let Some(max) = self.options.max_file_size
+            && self.written >= max
+        {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        if self.options.keep_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.options.keep_files).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.writer = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Drop for TraceLogger {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+
+    fn cpu_at_lda() -> CPU<Bus<'static>> {
+        let mut cpu = CPU::new(Bus::empty_bus());
+        // LDA #$01 in save RAM, the only writable place with a mock cartridge
+        cpu.bus.mem_write(0x6000, 0xA9);
+        cpu.bus.mem_write(0x6001, 0x01);
+        cpu.program_counter = 0x6000;
+        cpu.bus.tick(7);
+        cpu
+    }
+
+    #[test]
+    fn nintendulator_with_ppu() {
+        let mut cpu = cpu_at_lda();
+        assert_eq!(
+            trace_with_format(&mut cpu, TraceFormat::Nintendulator, true, None),
+            "6000  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+    }
+
+    #[test]
+    fn fceux_and_mesen() {
+        let mut cpu = cpu_at_lda();
+        assert_eq!(
+            trace_with_format(&mut cpu, TraceFormat::Fceux, false, None),
+            "c7           A:00 X:00 Y:00 S:FD P:nvubdIzc  $6000:A9 01     LDA #$01"
+        );
+        assert_eq!(
+            trace_with_format(&mut cpu, TraceFormat::Fceux, true, None),
+            "f0      c7           A:00 X:00 Y:00 S:FD P:nvubdIzc  $6000:A9 01     LDA #$01"
+        );
+        assert_eq!(
+            trace_with_format(&mut cpu, TraceFormat::Mesen, true, None),
+            "6000  $A9 $01      LDA #$01                        A:00 X:00 Y:00 P:nvUbdIzc SP:FD CYC:21  SL:0   FC:0 CPU Cycle:7"
+        );
+    }
+
+    #[test]
+    fn tracing_ppu_registers_leaves_them_alone() {
+        let mut cpu = CPU::new(Bus::empty_bus());
+        // LDA $2002, LDA $2007
+        for (i, byte) in [0xAD, 0x02, 0x20, 0xAD, 0x07, 0x20].into_iter().enumerate() {
+            cpu.bus.mem_write(0x6000 + i as u16, byte);
+        }
+        cpu.bus.mem_read(0x2002);
+        cpu.bus.mem_write(0x2006, 0x21);
+        cpu.bus.mem_write(0x2006, 0x00);
+        while !cpu.bus.ppu().status.is_in_vblank() {
+            cpu.bus.tick(1);
+        }
+
+        cpu.program_counter = 0x6000;
+        assert!(trace(&mut cpu).contains("LDA $2002 = 80"));
+        assert!(cpu.bus.ppu().status.is_in_vblank());
+
+        cpu.program_counter = 0x6003;
+        assert!(trace(&mut cpu).contains("LDA $2007 = 00"));
+        assert_eq!(cpu.bus.ppu().addr.get(), 0x2100);
+    }
+
+    #[test]
+    fn logger_filters_and_rotates() {
+        let dir = std::env::temp_dir().join(format!("nes-trace-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("trace.log");
+
+        let mut cpu = cpu_at_lda();
+        let mut logger = TraceLogger::new(
+            &path,
+            TraceOptions {
+                pc_ranges: vec![0x6000..=0x6000],
+                max_file_size: Some(1),
+                keep_files: 1,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        logger.log(&mut cpu).unwrap();
+        logger.log(&mut cpu).unwrap();
+        cpu.program_counter = 0x6001;
+        logger.log(&mut cpu).unwrap();
+        logger.flush().unwrap();
+
+        let rotated = fs::read_to_string(dir.join("trace.log.1")).unwrap();
+        assert!(rotated.starts_with("6000  A9 01"));
+        assert!(!dir.join("trace.log.2").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use nes_emulator::cartridge::Rom;
+use nes_emulator::headless::{InputScript, run_headless};
+use nes_emulator::render::frame::Frame;
+
+const ROMS_DIR: &str = "roms";
+const GOLDEN_DIR: &str = "tests/golden";
+const MANIFEST: &str = "tests/golden/frames.txt";
+// Set to rewrite the reference images with the current output
+const BLESS_VAR: &str = "GOLDEN_BLESS";
+
+struct Case {
+    rom: String,
+    script: Option<String>,
+    frame: usize,
+    image: String,
+}
+
+impl Case {
+    fn name(&self) -> String {
+        let rom = self.rom.trim_end_matches(".nes");
+        match &self.script {
+            Some(script) => format!("{}-{}-{}", rom, script.trim_end_matches(".input"), self.frame),
+            None => format!("{}-{}", rom, self.frame),
+        }
+    }
+}
+
+fn read_cases() -> Vec<Case> {
+    let text = fs::read_to_string(MANIFEST).unwrap();
+    text.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            assert_eq!(fields.len(), 4, "bad golden frame entry: {:?}", line);
+            Case {
+                rom: fields[0].to_string(),
+                script: (fields[1] != "-").then(|| fields[1].to_string()),
+                frame: fields[2].parse().unwrap(),
+                image: fields[3].to_string(),
+            }
+        })
+        .collect()
+}
+
+fn read_png(path: &Path) -> Frame {
+    let decoder = png::Decoder::new(File::open(path).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).unwrap();
+    assert_eq!(info.color_type, png::ColorType::Rgb, "{} is not RGB", path.display());
+    data.truncate(info.buffer_size());
+    Frame { data }
+}
+
+fn write_png(frame: &Frame, path: &Path) {
+    frame.write_png(BufWriter::new(File::create(path).unwrap())).unwrap();
+}
+
+// Pixels that differ in red, the rest a dimmed grey of the reference
+fn diff_image(actual: &Frame, expected: &Frame) -> Frame {
+    let mut diff = Frame::new();
+    let pixels = actual.data.chunks(3).zip(expected.data.chunks(3));
+    for (i, (actual, expected)) in pixels.enumerate() {
+        let rgb = if actual == expected {
+            let grey = ((expected[0] as u16 + expected[1] as u16 + expected[2] as u16) / 6) as u8;
+            [grey, grey, grey]
+        } else {
+            [0xFF, 0x00, 0x00]
+        };
+        diff.data[i * 3..i * 3 + 3].copy_from_slice(&rgb);
+    }
+    diff
+}
+
+// Runs every ROM + script pair once, up to the last frame any case wants
+fn render_cases(cases: &[Case]) -> BTreeMap<(String, Option<String>, usize), Frame> {
+    let mut runs: BTreeMap<(String, Option<String>), Vec<usize>> = BTreeMap::new();
+    for case in cases {
+        runs.entry((case.rom.clone(), case.script.clone()))
+            .or_default()
+            .push(case.frame);
+    }
+
+    let mut frames = BTreeMap::new();
+    for ((rom, script), wanted) in runs {
+        let bytes = fs::read(Path::new(ROMS_DIR).join(&rom)).unwrap();
+        let input = match &script {
+            Some(script) => InputScript::parse(&fs::read_to_string(Path::new(GOLDEN_DIR).join(script)).unwrap()).unwrap(),
+            None => InputScript::new(),
+        };
+        let last = *wanted.iter().max().unwrap();
+
+        run_headless(Rom::new(&bytes).unwrap(), last + 1, &input, |number, frame| {
+            if wanted.contains(&number) {
+                let copy = Frame { data: frame.data.clone() };
+                frames.insert((rom.clone(), script.clone(), number), copy);
+            }
+        })
+        .unwrap();
+    }
+    frames
+}
+
+#[test]
+fn golden_frames() {
+    let cases = read_cases();
+    let frames = render_cases(&cases);
+    let bless = env::var_os(BLESS_VAR).is_some();
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden-diffs");
+
+    let mut failures = Vec::new();
+    for case in &cases {
+        let actual = &frames[&(case.rom.clone(), case.script.clone(), case.frame)];
+        let name = case.name();
+
+        let path = Path::new(GOLDEN_DIR).join(&case.image);
+        if bless {
+            write_png(actual, &path);
+            continue;
+        }
+
+        let expected = read_png(&path);
+        if actual.data != expected.data {
+            fs::create_dir_all(&out_dir).unwrap();
+            let actual_path = out_dir.join(format!("{}.actual.png", name));
+            let diff_path = out_dir.join(format!("{}.diff.png", name));
+            write_png(actual, &actual_path);
+            write_png(&diff_image(actual, &expected), &diff_path);
+            failures.push(format!(
+                "{}: differs from {}, see {} and {}",
+                name,
+                path.display(),
+                actual_path.display(),
+                diff_path.display()
+            ));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} golden frame(s) changed, run with {}=1 to accept new images:\n{}",
+        failures.len(),
+        BLESS_VAR,
+        failures.join("\n")
+    );
+}
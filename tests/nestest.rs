@@ -0,0 +1,108 @@
+use std::fs;
+
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::{CPU, Mem};
+use nes_emulator::trace::{TraceFormat, trace_with_format};
+
+// Lines of context printed before the first divergent line
+const CONTEXT: usize = 5;
+
+// Splits a Nintendulator trace line into named fields:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+fn fields(line: &str) -> Vec<(&'static str, String)> {
+    let column = |from: usize, to: usize| line.get(from..to.min(line.len())).unwrap_or("").trim().to_string();
+    let asm = column(15, 48);
+
+    // Everything after the first `@` or `=` is what the instruction touches
+    let (instruction, operands) = match asm.find(['@', '=']) {
+        Some(pos) => (asm[..pos].trim().to_string(), asm[pos..].trim().to_string()),
+        None => (asm.clone(), String::new()),
+    };
+
+    let mut result = vec![
+        ("PC", column(0, 4)),
+        ("bytes", column(6, 15)),
+        ("instruction", instruction),
+        ("memory", operands),
+    ];
+
+    let registers = line.get(48..).unwrap_or("");
+    for name in ["A", "X", "Y", "P", "SP"] {
+        let value = registers
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix(&format!("{}:", name)))
+            .unwrap_or("");
+        result.push((name, value.to_string()));
+    }
+    let ppu = match (registers.find("PPU:"), registers.find("CYC:")) {
+        (Some(start), Some(end)) => registers[start + 4..end].replace(' ', ""),
+        _ => String::new(),
+    };
+    result.push(("PPU", ppu));
+    let cycles = registers.split("CYC:").nth(1).unwrap_or("").trim().to_string();
+    result.push(("CYC", cycles));
+    result
+}
+
+fn field_diff(expected: &str, actual: &str) -> String {
+    let mut report = String::new();
+    for ((name, want), (_, got)) in fields(expected).iter().zip(fields(actual).iter()) {
+        if want != got {
+            report.push_str(&format!("    {:<12} expected {:<20} got {}\n", name, want, got));
+        }
+    }
+    report
+}
+
+#[test]
+fn nestest_matches_reference_log() {
+    let reference = fs::read_to_string("reference.log").unwrap();
+    let reference: Vec<&str> = reference.lines().collect();
+
+    let bytes = fs::read("roms/nestest.nes").unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+    let mut cpu = CPU::new(Bus::new(rom, |_| {}));
+    cpu.reset();
+    // Automation mode starts at $C000 instead of the reset vector
+    cpu.program_counter = 0xC000;
+
+    let mut lines: Vec<String> = Vec::with_capacity(reference.len());
+    while lines.len() < reference.len() {
+        cpu.step(|cpu| {
+            lines.push(trace_with_format(cpu, TraceFormat::Nintendulator, true, None));
+        })
+        .unwrap();
+
+        let n = lines.len() - 1;
+        if lines[n] != reference[n] {
+            let mut report = format!("nestest diverged from reference.log at line {}\n", n + 1);
+            for line in &reference[n.saturating_sub(CONTEXT)..n] {
+                report.push_str(&format!("      {}\n", line));
+            }
+            report.push_str(&format!("    - {}\n", reference[n]));
+            report.push_str(&format!("    + {}\n", lines[n]));
+            report.push_str(&field_diff(reference[n], &lines[n]));
+            panic!("{}", report);
+        }
+    }
+
+    // nestest leaves its error codes for official and unofficial opcodes here
+    assert_eq!(cpu.bus.mem_read(0x02), 0x00, "official opcode tests failed");
+    assert_eq!(cpu.bus.mem_read(0x03), 0x00, "unofficial opcode tests failed");
+}
+
+#[test]
+fn field_diff_reports_registers_and_memory() {
+    let expected = "C68B  8D 15 40  STA $4015 = FF                  A:02 X:FF Y:15 P:25 SP:FB PPU:233, 84 CYC:26520";
+    let actual = "C68B  8D 15 40  STA $4015 = 99                  A:02 X:FF Y:15 P:24 SP:FB PPU:233, 87 CYC:26521";
+
+    let diff = field_diff(expected, actual);
+
+    assert!(diff.contains("memory"));
+    assert!(diff.contains("= FF"));
+    assert!(diff.contains("P "));
+    assert!(diff.contains("PPU"));
+    assert!(diff.contains("CYC"));
+    assert!(!diff.contains("instruction"));
+}
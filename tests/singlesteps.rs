@@ -1,47 +1,75 @@
+use std::env;
 use std::fs;
-use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use nes_emulator::bus::BusOP;
 use nes_emulator::cpu::{CPU, Mem};
+use nes_emulator::status_flags::StatusFlags;
 use nes_emulator::trace::trace;
 
 use serde::Deserialize;
 
+// Directory holding the nes6502 SingleStepTests (00.json ... ff.json)
+const TESTS_DIR_VAR: &str = "NES6502_TESTS_DIR";
+// Set to 0 to skip comparing every bus access against the `cycles` array
+const CHECK_BUS_VAR: &str = "NES6502_CHECK_BUS";
+// A trimmed down copy of the suite that ships with the repo
+const BUNDLED_TESTS_DIR: &str = "testfiles";
+
 macro_rules! assert_cpu_eq {
-    ($left:expr, $right:expr, $path:expr, $test_id:expr, $field_name:expr) => {
-        assert_eq!(
-            $left, $right,
-            "path: {}\n Test {} failed: {} mismatch — got {}, expected {}",
-            $path,
-            $test_id, $field_name, $left, $right
-        );
+    ($left:expr, $right:expr, $test_name:expr, $field_name:expr) => {
+        if $left != $right {
+            return Err(format!(
+                "Test {} failed: {} mismatch — got {}, expected {}",
+                $test_name, $field_name, $left, $right
+            ));
+        }
     };
 }
-// let's define our own simplified memory structure for Single Tests
-pub struct SimpleMem {
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// let's define our own simplified memory structure for Single Tests. It keeps
+// every access the CPU makes so they can be checked against the `cycles` array
+pub struct RecordingMem {
     mem: [u8; 65536],
     cycles: usize,
+    pub recording: bool,
+    pub accesses: Vec<(u16, u8, Access)>,
 }
 
-impl SimpleMem {
-    pub fn new() -> Self {
-        SimpleMem {
+impl RecordingMem {
+    fn new() -> Self {
+        RecordingMem {
             mem: [0; 65536],
             cycles: 0,
+            recording: true,
+            accesses: Vec::new(),
         }
     }
 }
 
-impl Mem for SimpleMem {
+impl Mem for RecordingMem {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+        let data = self.mem[addr as usize];
+        if self.recording {
+            self.accesses.push((addr, data, Access::Read));
+        }
+        data
     }
     fn mem_write(&mut self, addr: u16, data: u8) {
         self.mem[addr as usize] = data;
+        if self.recording {
+            self.accesses.push((addr, data, Access::Write));
+        }
     }
 }
 
-impl BusOP for SimpleMem {
+impl BusOP for RecordingMem {
     fn cycles(&mut self) -> usize {
         self.cycles
     }
@@ -74,115 +102,186 @@ pub struct CPUState {
     cycles: Vec<(u16, u8, String)>,
 }
 
+struct FileReport {
+    name: String,
+    passed: usize,
+    failures: Vec<String>,
+}
+
+fn check_bus() -> bool {
+    env::var(CHECK_BUS_VAR).map(|v| v != "0").unwrap_or(true)
+}
+
+fn setup_cpu(test_state: &CPUState) -> CPU<RecordingMem> {
+    let mut cpu = CPU::new(RecordingMem::new());
+    cpu.bus.recording = false;
+    for (addr, data) in &test_state.initial.ram {
+        cpu.bus.mem_write(*addr, *data);
+    }
+    cpu.bus.recording = true;
+
+    cpu.program_counter = test_state.initial.pc;
+    cpu.register_a = test_state.initial.a;
+    cpu.register_x = test_state.initial.x;
+    cpu.register_y = test_state.initial.y;
+    cpu.status = StatusFlags::from_bits_truncate(test_state.initial.p);
+    cpu.stack_pointer = test_state.initial.s;
+    cpu
+}
+
+fn check_final_state(cpu: &mut CPU<RecordingMem>, test_state: &CPUState, check_bus: bool) -> Result<(), String> {
+    let name = &test_state.name;
+    assert_cpu_eq!(cpu.register_a, test_state.r#final.a, name, "Register a");
+    assert_cpu_eq!(cpu.register_x, test_state.r#final.x, name, "Register x");
+    assert_cpu_eq!(cpu.register_y, test_state.r#final.y, name, "Register y");
+    assert_cpu_eq!(cpu.stack_pointer, test_state.r#final.s, name, "Stack Pointer");
+    assert_cpu_eq!(cpu.status.bits(), test_state.r#final.p, name, "Status flag");
+    assert_cpu_eq!(cpu.program_counter, test_state.r#final.pc, name, "PC");
+    assert_cpu_eq!(cpu.bus.cycles(), test_state.cycles.len(), name, "cycles");
+
+    if check_bus {
+        let expected: Vec<(u16, u8, Access)> = test_state
+            .cycles
+            .iter()
+            .map(|(addr, data, kind)| {
+                let access = if kind == "write" { Access::Write } else { Access::Read };
+                (*addr, *data, access)
+            })
+            .collect();
+        if cpu.bus.accesses != expected {
+            let cycle = cpu
+                .bus
+                .accesses
+                .iter()
+                .zip(expected.iter())
+                .position(|(got, want)| got != want)
+                .unwrap_or(expected.len().min(cpu.bus.accesses.len()));
+            return Err(format!(
+                "Test {} failed: bus access {} mismatch — got {:?}, expected {:?}",
+                name,
+                cycle + 1,
+                cpu.bus.accesses.get(cycle),
+                expected.get(cycle)
+            ));
+        }
+    }
+
+    cpu.bus.recording = false;
+    for (addr, data) in &test_state.r#final.ram {
+        let my_data = cpu.bus.mem_read(*addr);
+        if my_data != *data {
+            return Err(format!(
+                "Test {0}: RAM @ 0x{1:04X}({1}) = {2:02X}({2}), but should be {3:02X}({3})",
+                name, *addr, my_data, *data
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn run_file(path: &Path, check_bus: bool) -> FileReport {
+    let contents = fs::read_to_string(path).unwrap();
+    let data: Vec<CPUState> = serde_json::from_str(&contents).unwrap();
+
+    let mut report = FileReport {
+        name: path.file_name().unwrap().to_string_lossy().to_string(),
+        passed: 0,
+        failures: Vec::new(),
+    };
+    for test_state in &data {
+        let mut cpu = setup_cpu(test_state);
+        // The JAM opcodes halt the CPU, which step reports as an error
+        if let Err(err) = cpu.step(|_| {})
+            && !cpu.halted
+        {
+            report.failures.push(format!("Test {} failed: {}", test_state.name, err));
+            continue;
+        }
+        match check_final_state(&mut cpu, test_state, check_bus) {
+            Ok(()) => report.passed += 1,
+            Err(failure) => report.failures.push(failure),
+        }
+    }
+    report
+}
+
+fn run_dir(dir: &Path) {
+    let check_bus = check_bus();
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    paths.sort();
+
+    let reports: Vec<FileReport> = paths.iter().map(|path| run_file(path, check_bus)).collect();
+
+    let mut failed_files = 0;
+    for report in &reports {
+        let total = report.passed + report.failures.len();
+        if report.failures.is_empty() {
+            println!("{:>8}: {:>5}/{} ok", report.name, report.passed, total);
+        } else {
+            failed_files += 1;
+            println!(
+                "{:>8}: {:>5}/{} FAILED, first: {}",
+                report.name, report.passed, total, report.failures[0]
+            );
+        }
+    }
+    assert!(
+        failed_files == 0,
+        "{} of {} opcode files had failures (see the per-file report above)",
+        failed_files,
+        reports.len()
+    );
+}
+
 #[test]
 fn run_singlesteps() {
-    for entry in fs::read_dir("/Users/pedroabreu/gitprojects/65x02/nes6502/v1").unwrap() {
-        let path = entry.unwrap().path();
-        // println!("\n***************************************************\n");
-        // println!("Running File {}", path.to_str().unwrap());
-
-        let mut file = fs::File::open(&path).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let data: Vec<CPUState> = serde_json::from_str(&contents).unwrap();
-
-        let mut i: i32 = 1;
-        for test_state in &data {
-            // println!("\n==============================");
-            // println!("Running File {}", path.to_str().unwrap());
-            // println!("Running test {}", i);
-            // println!("name: {}", test_state.name);
-            let str_path = path.to_str().unwrap();
-            let mut cpu = CPU::new(SimpleMem::new());
-            for (addr, data) in &test_state.initial.ram {
-                // println!("Writing addr: {:04X}, data: {:02X}", *addr, *data);
-                cpu.mem_write(*addr, *data);
-            }
-            cpu.program_counter = test_state.initial.pc;
-            // cpu.reset();
-            cpu.register_a = test_state.initial.a;
-            cpu.register_x = test_state.initial.x;
-            cpu.register_y = test_state.initial.y;
-            cpu.status = test_state.initial.p;
-            cpu.stack_pointer = test_state.initial.s;
-
-            // println!("Started step");
-            cpu.step(|cpu| {
-                // println!("{}", trace(cpu));
-            });
-            // println!("Finished step");
-            assert_cpu_eq!(cpu.register_a, test_state.r#final.a, str_path, i, "Register a");
-            assert_cpu_eq!(cpu.register_x, test_state.r#final.x, str_path, i, "Register x");
-            assert_cpu_eq!(cpu.register_y, test_state.r#final.y, str_path, i, "Register y");
-            assert_cpu_eq!(cpu.stack_pointer, test_state.r#final.s, str_path, i, "Stack Pointer");
-            assert_cpu_eq!(cpu.status, test_state.r#final.p, str_path, i, "Status flag");
-            assert_cpu_eq!(cpu.program_counter, test_state.r#final.pc, str_path, i, "PC");
-            assert_cpu_eq!(cpu.bus.cycles(), test_state.cycles.len(), str_path, i, "cycles");
-
-            for (addr, data) in &test_state.r#final.ram {
-                // println!("Writing addr: {:04X}, data: {:02X}", *addr, *data);
-                let my_data = cpu.mem_read(*addr);
-                assert_eq!(
-                    my_data, *data,
-                    "path: {0}\n Test: {4}, RAM @ 0x{1:04X}({1}) = {2:02X}({2}), but should be {3:02X}({3})",
-                    str_path, *addr, my_data, *data, i
-                );
-            }
-
-            i += 1;
+    let dir = match env::var_os(TESTS_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            println!("{} is not set, skipping the SingleStepTests suite", TESTS_DIR_VAR);
+            return;
         }
+    };
+    if !dir.is_dir() {
+        println!("{} does not exist, skipping the SingleStepTests suite", dir.display());
+        return;
     }
+    run_dir(&dir);
+}
+
+#[test]
+fn run_bundled_singlesteps() {
+    run_dir(Path::new(BUNDLED_TESTS_DIR));
 }
 
 #[test]
 fn run_a_singlestep() {
-    let contents = include_str!("/Users/pedroabreu/gitprojects/65x02/nes6502/v1/00.json");
+    let contents = fs::read_to_string(Path::new(BUNDLED_TESTS_DIR).join("1c.json")).unwrap();
     let data: Vec<CPUState> = serde_json::from_str(&contents).unwrap();
 
-    let str_path = "";
-    let i: i32 = 0;
-    let test_state = &data[i as usize];
+    let test_state = &data[0];
     println!("\n==============================");
-    println!("Running test {}", i);
     println!("name: {}", test_state.name);
 
-    let mut cpu = CPU::new(SimpleMem::new());
-
-    for (addr, data) in &test_state.initial.ram {
-        // println!("Writing addr: {:04X}, data: {:02X}", *addr, *data);
-        cpu.mem_write(*addr, *data);
-    }
-    cpu.program_counter = test_state.initial.pc;
-    // cpu.reset();
-    cpu.register_a = test_state.initial.a;
-    cpu.register_x = test_state.initial.x;
-    cpu.register_y = test_state.initial.y;
-    cpu.status = test_state.initial.p;
-    cpu.stack_pointer = test_state.initial.s;
+    let mut cpu = setup_cpu(test_state);
 
     println!("Started step");
     cpu.step(|cpu| {
+        cpu.bus.recording = false;
         println!("{}", trace(cpu));
-    });
+        cpu.bus.recording = true;
+    })
+    .unwrap();
     println!("Finished step");
-            assert_cpu_eq!(cpu.register_a, test_state.r#final.a, str_path, i, "Register a");
-            assert_cpu_eq!(cpu.register_x, test_state.r#final.x, str_path, i, "Register x");
-            assert_cpu_eq!(cpu.register_y, test_state.r#final.y, str_path, i, "Register y");
-            assert_cpu_eq!(cpu.stack_pointer, test_state.r#final.s, str_path, i, "Stack Pointer");
-            assert_cpu_eq!(cpu.status, test_state.r#final.p, str_path, i, "Status flag");
-            assert_cpu_eq!(cpu.program_counter, test_state.r#final.pc, str_path, i, "PC");
-            assert_cpu_eq!(cpu.bus.cycles(), test_state.cycles.len(), str_path, i, "cycles");
-
-    for (addr, data) in &test_state.r#final.ram {
-        let my_data = cpu.mem_read(*addr);
-
-        println!("RAM @ 0x{0:04X}({0}) = {1:02X}({1}), should be {2:02X}({2})",
-            *addr, my_data, *data);
+    for (addr, data, access) in &cpu.bus.accesses {
+        println!("{:04X} {:02X} {:?}", addr, data, access);
+    }
 
-        assert_eq!(
-            my_data, *data,
-            "RAM @ 0x{0:04X}({0}) = {1:02X}({1}), but should be {2:02X}({2})",
-            *addr, my_data, *data
-        );
+    if let Err(failure) = check_final_state(&mut cpu, test_state, check_bus()) {
+        panic!("{}", failure);
     }
 }
@@ -0,0 +1,51 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use nes_emulator::cartridge::Rom;
+use nes_emulator::test_rom::{CPU_CYCLES_PER_SECOND, run_test_rom};
+
+// Directory with blargg style test ROMs (instr_test, ppu_vbl_nmi, ...)
+const TEST_ROMS_DIR_VAR: &str = "NES_TEST_ROMS_DIR";
+// Emulated seconds before a ROM is considered stuck
+const TIMEOUT_SECONDS: usize = 60;
+
+#[test]
+fn run_test_roms() {
+    let dir = match env::var_os(TEST_ROMS_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            println!("{} is not set, skipping test ROMs", TEST_ROMS_DIR_VAR);
+            return;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "nes"))
+        .collect();
+    paths.sort();
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let bytes = fs::read(path).unwrap();
+        let outcome = Rom::new(&bytes)
+            .and_then(|rom| run_test_rom(rom, TIMEOUT_SECONDS * CPU_CYCLES_PER_SECOND));
+
+        match outcome {
+            Ok(result) if result.passed() => println!("{:>32}: passed", name),
+            Ok(result) => {
+                println!("{:>32}: {:?}\n{}", name, result.status, result.message);
+                failures.push(name);
+            }
+            Err(error) => {
+                println!("{:>32}: could not run: {}", name, error);
+                failures.push(name);
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{} of {} test ROMs failed: {:?}", failures.len(), paths.len(), failures);
+}